@@ -0,0 +1,94 @@
+// Page fault counts through getrusage. RUSAGE_THREAD is used so counts line up with the profiler's
+// per-thread tables. The resident set size read alongside them (/proc/self/statm) is the whole
+// process's, so a block's RSS delta includes what other threads mapped or freed meanwhile.
+// Only available on Linux, other platforms always read None.
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PageFaults {
+    pub minor: u64, // Serviced without I/O, e.g. first touch of a freshly mapped page
+    pub major: u64, // Required I/O
+    pub resident_kb: u64, // Differences wrap below zero when the process returned memory, see resident_kb_delta
+}
+
+impl PageFaults {
+    pub fn total(&self) -> u64 { self.minor + self.major }
+
+    // For differences of two readings, negative when the resident set shrank
+    pub fn resident_kb_delta(&self) -> i64 { self.resident_kb as i64 }
+
+    #[inline(always)]
+    pub fn wrapping_add(&self, other: &PageFaults) -> PageFaults {
+        PageFaults { minor: self.minor.wrapping_add(other.minor), major: self.major.wrapping_add(other.major), resident_kb: self.resident_kb.wrapping_add(other.resident_kb) }
+    }
+
+    #[inline(always)]
+    pub fn wrapping_sub(&self, other: &PageFaults) -> PageFaults {
+        PageFaults { minor: self.minor.wrapping_sub(other.minor), major: self.major.wrapping_sub(other.major), resident_kb: self.resident_kb.wrapping_sub(other.resident_kb) }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn getrusage(who: libc::c_int) -> Option<libc::rusage> {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    if unsafe { libc::getrusage(who, &mut usage) } == 0 { Some(usage) } else { None }
+}
+
+// Faults taken by the calling thread so far, with the process's current resident set size
+#[cfg(target_os = "linux")]
+#[inline(always)]
+pub fn read_page_faults() -> Option<PageFaults> {
+    let resident_kb = read_resident_kb().unwrap_or(0);
+    getrusage(libc::RUSAGE_THREAD).map(|usage| PageFaults { minor: usage.ru_minflt as u64, major: usage.ru_majflt as u64, resident_kb })
+}
+
+// Current resident set size of the whole process. /proc/self/statm is opened once and re-read in place.
+#[cfg(target_os = "linux")]
+pub fn read_resident_kb() -> Option<u64> {
+    use std::os::unix::fs::FileExt;
+
+    static STATM: std::sync::OnceLock<Option<std::fs::File>> = std::sync::OnceLock::new();
+    static PAGE_KB: std::sync::OnceLock<u64> = std::sync::OnceLock::new();
+    let statm = STATM.get_or_init(|| std::fs::File::open("/proc/self/statm").ok()).as_ref()?;
+    let page_kb = *PAGE_KB.get_or_init(|| unsafe { libc::sysconf(libc::_SC_PAGESIZE) }.max(0) as u64 / 1024);
+    // "size resident shared text lib data dt", in pages
+    let mut buffer = [0u8; 128];
+    let bytes_read = statm.read_at(&mut buffer, 0).ok()?;
+    let resident_pages = std::str::from_utf8(&buffer[..bytes_read]).ok()?.split_whitespace().nth(1)?.parse::<u64>().ok()?;
+    Some(resident_pages * page_kb)
+}
+
+// Peak resident set size of the whole process
+#[cfg(target_os = "linux")]
+pub fn read_max_rss_kb() -> Option<u64> {
+    getrusage(libc::RUSAGE_SELF).map(|usage| usage.ru_maxrss as u64)
+}
+
+#[cfg(not(target_os = "linux"))]
+#[inline(always)]
+pub fn read_page_faults() -> Option<PageFaults> { None }
+
+#[cfg(not(target_os = "linux"))]
+pub fn read_resident_kb() -> Option<u64> { None }
+
+#[cfg(not(target_os = "linux"))]
+pub fn read_max_rss_kb() -> Option<u64> { None }
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn touching_fresh_pages_faults() {
+        let start = read_page_faults().expect("getrusage failed");
+        // Large enough to be mmapped fresh instead of reusing heap pages
+        let mut pages = vec![0u8; 64 * 1024 * 1024];
+        for i in (0..pages.len()).step_by(4096) { pages[i] = 1; }
+        std::hint::black_box(&pages);
+        let faults = read_page_faults().expect("getrusage failed").wrapping_sub(&start);
+        assert!(faults.minor > 0);
+        assert!(faults.resident_kb_delta() >= 32 * 1024, "{:?}", faults);
+        drop(pages);
+        assert!(read_page_faults().unwrap().wrapping_sub(&start).resident_kb_delta() < 32 * 1024);
+        assert!(read_max_rss_kb().unwrap() > 0);
+    }
+}
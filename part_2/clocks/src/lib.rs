@@ -1,29 +1,289 @@
+#[cfg(any(target_os = "windows", target_os = "linux"))]
 use std::mem;
-use core::arch::x86_64::_rdtsc;
+use std::sync::OnceLock;
 
+pub mod perf;
+pub mod faults;
+
+#[cfg(target_arch = "x86_64")]
+use core::arch::x86_64::{_rdtsc, __rdtscp, __cpuid, _mm_lfence};
+#[cfg(target_arch = "x86")]
+use core::arch::x86::{_rdtsc, __rdtscp, __cpuid, _mm_lfence};
+
+// The counter read by read_cpu_timer() is chosen at compile time:
+//  - x86/x86_64: rdtsc
+//  - aarch64: virtual counter register (cntvct_el0)
+//  - everything else: the OS monotonic timer
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CpuTimerSource {
+    Tsc,
+    VirtualCounter,
+    OsTimer
+}
+
+#[cfg(any(target_arch = "x86_64", target_arch = "x86"))]
+pub const CPU_TIMER_SOURCE: CpuTimerSource = CpuTimerSource::Tsc;
+#[cfg(target_arch = "aarch64")]
+pub const CPU_TIMER_SOURCE: CpuTimerSource = CpuTimerSource::VirtualCounter;
+#[cfg(not(any(target_arch = "x86_64", target_arch = "x86", target_arch = "aarch64")))]
+pub const CPU_TIMER_SOURCE: CpuTimerSource = CpuTimerSource::OsTimer;
+
+#[cfg(any(target_arch = "x86_64", target_arch = "x86"))]
 #[inline(always)]
 pub fn read_cpu_timer() -> u64 {
     unsafe { _rdtsc() }
 }
 
-pub fn measure_cpu_freq(measure_time_ms: u64) -> u64 {
+#[cfg(target_arch = "aarch64")]
+#[inline(always)]
+pub fn read_cpu_timer() -> u64 {
+    let counter: u64;
+    unsafe { core::arch::asm!("mrs {}, cntvct_el0", out(reg) counter, options(nomem, nostack)); }
+    counter
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "x86", target_arch = "aarch64")))]
+#[inline(always)]
+pub fn read_cpu_timer() -> u64 {
+    read_os_timer()
+}
+
+// Serializing reads of the CPU timer. A bare read_cpu_timer() can be reordered with the
+// surrounding instructions, which smears the timing of very short blocks. These variants
+// trade extra overhead per read for keeping the measured work on the right side of the stamp.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CpuTimerRead {
+    #[default]
+    Unserialized, // rdtsc
+    Rdtscp,       // waits for all prior instructions to execute
+    LfenceRdtsc,  // lfence + rdtsc + lfence
+    CpuidRdtsc,   // cpuid (fully serializing) + rdtsc
+}
+
+impl CpuTimerRead {
+    pub const ALL: [CpuTimerRead; 4] = [CpuTimerRead::Unserialized, CpuTimerRead::Rdtscp, CpuTimerRead::LfenceRdtsc, CpuTimerRead::CpuidRdtsc];
+
+    #[inline(always)]
+    pub fn read(self) -> u64 {
+        match self {
+            CpuTimerRead::Unserialized => read_cpu_timer(),
+            CpuTimerRead::Rdtscp => read_cpu_timer_rdtscp(),
+            CpuTimerRead::LfenceRdtsc => read_cpu_timer_lfence(),
+            CpuTimerRead::CpuidRdtsc => read_cpu_timer_cpuid(),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            CpuTimerRead::Unserialized => "rdtsc",
+            CpuTimerRead::Rdtscp => "rdtscp",
+            CpuTimerRead::LfenceRdtsc => "lfence",
+            CpuTimerRead::CpuidRdtsc => "cpuid",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<CpuTimerRead> {
+        CpuTimerRead::ALL.into_iter().find(|read| read.name() == name)
+    }
+}
+
+#[cfg(any(target_arch = "x86_64", target_arch = "x86"))]
+#[inline(always)]
+pub fn read_cpu_timer_rdtscp() -> u64 {
+    let mut aux: u32 = 0;
+    unsafe { __rdtscp(&mut aux) }
+}
+
+#[cfg(any(target_arch = "x86_64", target_arch = "x86"))]
+#[inline(always)]
+pub fn read_cpu_timer_lfence() -> u64 {
+    unsafe {
+        _mm_lfence();
+        let stamp = _rdtsc();
+        _mm_lfence();
+        stamp
+    }
+}
+
+#[cfg(any(target_arch = "x86_64", target_arch = "x86"))]
+#[inline(always)]
+pub fn read_cpu_timer_cpuid() -> u64 {
+    unsafe {
+        __cpuid(0);
+        _rdtsc()
+    }
+}
+
+// aarch64 has a single way of ordering the counter read (isb), all variants use it
+#[cfg(target_arch = "aarch64")]
+#[inline(always)]
+fn read_cpu_timer_isb() -> u64 {
+    let counter: u64;
+    unsafe { core::arch::asm!("isb", "mrs {}, cntvct_el0", out(reg) counter, options(nostack)); }
+    counter
+}
 
-    #[cfg(target_os = "windows")]
-    fn get_os_timer_freq() -> u64 { unsafe {
-        let mut freq = mem::zeroed();
-        winapi::um::profileapi::QueryPerformanceFrequency(&mut freq);
-        *freq.QuadPart() as u64
-    }}
+#[cfg(target_arch = "aarch64")]
+#[inline(always)]
+pub fn read_cpu_timer_rdtscp() -> u64 { read_cpu_timer_isb() }
+
+#[cfg(target_arch = "aarch64")]
+#[inline(always)]
+pub fn read_cpu_timer_lfence() -> u64 { read_cpu_timer_isb() }
+
+#[cfg(target_arch = "aarch64")]
+#[inline(always)]
+pub fn read_cpu_timer_cpuid() -> u64 { read_cpu_timer_isb() }
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "x86", target_arch = "aarch64")))]
+#[inline(always)]
+pub fn read_cpu_timer_rdtscp() -> u64 { read_os_timer() }
 
-    #[cfg(target_os = "windows")]
-    fn read_os_timer() -> u64 { unsafe {
-        let mut freq = mem::zeroed();
-        winapi::um::profileapi::QueryPerformanceCounter(&mut freq);
-        *freq.QuadPart() as u64
-    }}
+#[cfg(not(any(target_arch = "x86_64", target_arch = "x86", target_arch = "aarch64")))]
+#[inline(always)]
+pub fn read_cpu_timer_lfence() -> u64 { read_os_timer() }
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "x86", target_arch = "aarch64")))]
+#[inline(always)]
+pub fn read_cpu_timer_cpuid() -> u64 { read_os_timer() }
+
+// Minimum number of clocks observed between two back-to-back reads with the given method.
+pub fn measure_timer_read_overhead(timer_read: CpuTimerRead, sample_count: u32) -> u64 {
+    assert!(sample_count > 0, "sample_count must be greater than 0.");
+
+    let mut min_clocks = u64::MAX;
+    for _ in 0..sample_count {
+        let start = timer_read.read();
+        let end = timer_read.read();
+        min_clocks = min_clocks.min(end.wrapping_sub(start));
+    }
+    min_clocks
+}
+
+#[cfg(target_os = "windows")]
+pub fn get_os_timer_freq() -> u64 { unsafe {
+    let mut freq = mem::zeroed();
+    winapi::um::profileapi::QueryPerformanceFrequency(&mut freq);
+    *freq.QuadPart() as u64
+}}
+
+#[cfg(target_os = "windows")]
+pub fn read_os_timer() -> u64 { unsafe {
+    let mut freq = mem::zeroed();
+    winapi::um::profileapi::QueryPerformanceCounter(&mut freq);
+    *freq.QuadPart() as u64
+}}
+
+#[cfg(target_os = "linux")]
+pub fn get_os_timer_freq() -> u64 { 1_000_000_000 } // CLOCK_MONOTONIC is reported in nanoseconds
+
+#[cfg(target_os = "linux")]
+pub fn read_os_timer() -> u64 { unsafe {
+    let mut time: libc::timespec = mem::zeroed();
+    libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut time);
+    (time.tv_sec as u64 * 1_000_000_000) + time.tv_nsec as u64
+}}
+
+#[cfg(not(any(target_os = "windows", target_os = "linux")))]
+pub fn get_os_timer_freq() -> u64 { 1_000_000_000 } // Instant is reported in nanoseconds
+
+#[cfg(not(any(target_os = "windows", target_os = "linux")))]
+pub fn read_os_timer() -> u64 {
+    use std::time::Instant;
+    static EPOCH: OnceLock<Instant> = OnceLock::new();
+    EPOCH.get_or_init(Instant::now).elapsed().as_nanos() as u64
+}
 
+// What the hardware reports about the CPU timer. On x86/x86_64 this comes from cpuid.
+// On other targets the vendor/brand are empty and the tsc fields describe the counter
+// that read_cpu_timer() uses, which is always fixed frequency there.
+#[derive(Clone, Debug, Default)]
+pub struct CpuInfo {
+    pub vendor: String,
+    pub brand: String,
+    pub invariant_tsc: bool,    // TSC ticks at a constant rate regardless of power states
+    pub tsc_freq: Option<u64>,  // Nominal TSC frequency in Hz, if the hardware reports it
+    pub base_freq: Option<u64>, // Processor base frequency in Hz, for display only as the TSC may run at another rate
+}
+
+// Queried once and cached for the rest of the process
+pub fn cpu_info() -> &'static CpuInfo {
+    static CPU_INFO: OnceLock<CpuInfo> = OnceLock::new();
+    CPU_INFO.get_or_init(query_cpu_info)
+}
+
+#[cfg(any(target_arch = "x86_64", target_arch = "x86"))]
+fn query_cpu_info() -> CpuInfo {
+    let mut info = CpuInfo::default();
+
+    let leaf_0 = __cpuid(0);
+    let max_leaf = leaf_0.eax;
+    let vendor_bytes: Vec<u8> = [leaf_0.ebx, leaf_0.edx, leaf_0.ecx].iter().flat_map(|reg| reg.to_le_bytes()).collect();
+    info.vendor = String::from_utf8_lossy(&vendor_bytes).trim_end_matches('\0').to_string();
+
+    let max_extended_leaf = __cpuid(0x8000_0000).eax;
+    if max_extended_leaf >= 0x8000_0004 {
+        let brand_bytes: Vec<u8> = (0x8000_0002..=0x8000_0004_u32)
+            .map(__cpuid)
+            .flat_map(|regs| [regs.eax, regs.ebx, regs.ecx, regs.edx])
+            .flat_map(|reg| reg.to_le_bytes())
+            .collect();
+        info.brand = String::from_utf8_lossy(&brand_bytes).trim_end_matches('\0').trim().to_string();
+    }
+    if max_extended_leaf >= 0x8000_0007 {
+        info.invariant_tsc = (__cpuid(0x8000_0007).edx & (1 << 8)) != 0;
+    }
+
+    // Leaf 0x15: TSC/crystal clock ratio (eax = denominator, ebx = numerator, ecx = crystal Hz)
+    if max_leaf >= 0x15 {
+        let leaf_15 = __cpuid(0x15);
+        if leaf_15.eax != 0 && leaf_15.ebx != 0 && leaf_15.ecx != 0 {
+            info.tsc_freq = Some(leaf_15.ecx as u64 * leaf_15.ebx as u64 / leaf_15.eax as u64);
+        }
+    }
+    // Leaf 0x16: processor base frequency in MHz. Not the TSC frequency: it is rounded to whole MHz and
+    // the TSC does not run at base frequency on every part, so the TSC is calibrated when 0x15 is missing.
+    if max_leaf >= 0x16 {
+        let base_mhz = __cpuid(0x16).eax & 0xFFFF;
+        if base_mhz != 0 {
+            info.base_freq = Some(base_mhz as u64 * 1_000_000);
+        }
+    }
+
+    info
+}
+
+#[cfg(target_arch = "aarch64")]
+fn query_cpu_info() -> CpuInfo {
+    // Frequency of the virtual counter as reported by the hardware (cntfrq_el0)
+    let freq: u64;
+    unsafe { core::arch::asm!("mrs {}, cntfrq_el0", out(reg) freq, options(nomem, nostack)); }
+    CpuInfo { invariant_tsc: true, tsc_freq: if freq != 0 { Some(freq) } else { None }, ..Default::default() }
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "x86", target_arch = "aarch64")))]
+fn query_cpu_info() -> CpuInfo {
+    CpuInfo { invariant_tsc: true, tsc_freq: Some(get_os_timer_freq()), ..Default::default() }
+}
+
+// Uses the frequency reported by the hardware when it can be trusted and only falls back
+// to busy-wait calibration against the OS timer when it cannot.
+pub fn measure_cpu_freq(measure_time_ms: u64) -> u64 {
+    assert!(measure_time_ms > 0, "measure_time_ms must be greater than 0.");
+
+    let info = cpu_info();
+    match (info.invariant_tsc, info.tsc_freq) {
+        (true, Some(tsc_freq)) => tsc_freq,
+        _ => calibrate_cpu_freq(measure_time_ms)
+    }
+}
+
+// Always measures the CPU timer against the OS timer for measure_time_ms.
+pub fn calibrate_cpu_freq(measure_time_ms: u64) -> u64 {
     assert!(measure_time_ms > 0, "measure_time_ms must be greater than 0.");
 
+    if CPU_TIMER_SOURCE == CpuTimerSource::OsTimer { return get_os_timer_freq(); }
+
     let os_clocks_per_sec = get_os_timer_freq();
     let os_wait_clocks = os_clocks_per_sec / 1000 * measure_time_ms;
 
@@ -41,5 +301,149 @@ pub fn measure_cpu_freq(measure_time_ms: u64) -> u64 {
     return cpu_clocks_per_second;
 }
 
+// Result of calibrating the CPU timer against the OS timer over several short samples.
+// A single long sample can be skewed by preemption, the median of several short ones is not.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CpuFreqCalibration {
+    pub median: u64,
+    pub min: u64,
+    pub max: u64,
+    pub sample_count: u32, // 0 when the frequency was reported by the hardware
+}
+
+impl CpuFreqCalibration {
+    pub fn from_samples(samples: &mut [u64]) -> CpuFreqCalibration {
+        assert!(!samples.is_empty(), "samples must not be empty.");
+        samples.sort_unstable();
+        let mid = samples.len() / 2;
+        let median = if samples.len().is_multiple_of(2) { (samples[mid - 1] + samples[mid]) / 2 } else { samples[mid] };
+        CpuFreqCalibration {
+            median,
+            min: samples[0],
+            max: samples[samples.len() - 1],
+            sample_count: samples.len() as u32
+        }
+    }
+
+    pub fn from_known_freq(freq: u64) -> CpuFreqCalibration {
+        CpuFreqCalibration { median: freq, min: freq, max: freq, sample_count: 0 }
+    }
+
+    pub fn spread(&self) -> u64 { self.max - self.min }
+    pub fn spread_percent(&self) -> f64 { self.spread() as f64 / self.median as f64 * 100.0 }
+}
+
+pub const CPU_FREQ_SAMPLE_COUNT: u32 = 9;
+pub const CPU_FREQ_SAMPLE_TIME_MS: u64 = 10;
+// When set, calibrations are persisted to (and loaded from) this file, keyed by CPU brand.
+pub const CPU_FREQ_CACHE_ENV: &str = "CLOCKS_CPU_FREQ_CACHE";
+
+pub fn calibrate_cpu_freq_samples(sample_count: u32, sample_time_ms: u64) -> CpuFreqCalibration {
+    assert!(sample_count > 0, "sample_count must be greater than 0.");
+    let mut samples: Vec<u64> = (0..sample_count).map(|_| calibrate_cpu_freq(sample_time_ms)).collect();
+    CpuFreqCalibration::from_samples(&mut samples)
+}
+
+// Process-wide CPU frequency. The first call decides, in order of preference:
+//  - the frequency reported by the hardware (invariant TSC only)
+//  - a calibration persisted in the CPU_FREQ_CACHE_ENV file for this CPU
+//  - a fresh multi-sample calibration (persisted if CPU_FREQ_CACHE_ENV is set)
+pub fn cached_cpu_freq() -> &'static CpuFreqCalibration {
+    static CALIBRATION: OnceLock<CpuFreqCalibration> = OnceLock::new();
+    CALIBRATION.get_or_init(|| {
+        let info = cpu_info();
+        if let (true, Some(tsc_freq)) = (info.invariant_tsc, info.tsc_freq) {
+            return CpuFreqCalibration::from_known_freq(tsc_freq);
+        }
+
+        let cache_path = std::env::var(CPU_FREQ_CACHE_ENV).ok();
+        let cache_key = if info.brand.is_empty() { "unknown" } else { info.brand.as_str() };
+        if let Some(calibration) = cache_path.as_ref().and_then(|path| load_cpu_freq_cache(path, cache_key)) {
+            return calibration;
+        }
+
+        let calibration = calibrate_cpu_freq_samples(CPU_FREQ_SAMPLE_COUNT, CPU_FREQ_SAMPLE_TIME_MS);
+        if let Some(path) = cache_path {
+            if let Err(e) = save_cpu_freq_cache(&path, cache_key, &calibration) {
+                eprintln!("WARNING: Failed to write CPU frequency cache {}: {}", path, e);
+            }
+        }
+        calibration
+    })
+}
+
+// Cache file format is one line per CPU: "<brand>\t<median>\t<min>\t<max>\t<sample count>"
+fn parse_cpu_freq_cache_line(line: &str) -> Option<(&str, CpuFreqCalibration)> {
+    let mut fields = line.split('\t');
+    let key = fields.next()?;
+    let mut next_u64 = || fields.next()?.parse::<u64>().ok();
+    let calibration = CpuFreqCalibration {
+        median: next_u64()?,
+        min: next_u64()?,
+        max: next_u64()?,
+        sample_count: next_u64()? as u32
+    };
+    Some((key, calibration))
+}
+
+fn format_cpu_freq_cache_line(key: &str, calibration: &CpuFreqCalibration) -> String {
+    format!("{}\t{}\t{}\t{}\t{}", key, calibration.median, calibration.min, calibration.max, calibration.sample_count)
+}
+
+fn load_cpu_freq_cache(path: &str, key: &str) -> Option<CpuFreqCalibration> {
+    let contents = std::fs::read_to_string(path).ok()?;
+    contents.lines()
+        .filter_map(parse_cpu_freq_cache_line)
+        .find(|(line_key, _)| *line_key == key)
+        .map(|(_, calibration)| calibration)
+}
+
+fn save_cpu_freq_cache(path: &str, key: &str, calibration: &CpuFreqCalibration) -> std::io::Result<()> {
+    let contents = std::fs::read_to_string(path).unwrap_or_default();
+    let mut lines: Vec<String> = contents.lines()
+        .filter(|line| parse_cpu_freq_cache_line(line).is_some_and(|(line_key, _)| line_key != key))
+        .map(String::from)
+        .collect();
+    lines.push(format_cpu_freq_cache_line(key, calibration));
+    std::fs::write(path, lines.join("\n") + "\n")
+}
+
 pub fn clocks_to_secs(clocks: u64, cpu_freq: u64) -> f64 { clocks as f64 / cpu_freq as f64 }
-pub fn clocks_to_millisecs(clocks: u64, cpu_freq: u64) -> f64 { clocks_to_secs(clocks, cpu_freq) * 1000.0 }
\ No newline at end of file
+pub fn clocks_to_millisecs(clocks: u64, cpu_freq: u64) -> f64 { clocks_to_secs(clocks, cpu_freq) * 1000.0 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn calibration_median_and_spread() {
+        let mut odd_samples = [30, 10, 20];
+        let calibration = CpuFreqCalibration::from_samples(&mut odd_samples);
+        assert_eq!(calibration.median, 20);
+        assert_eq!(calibration.spread(), 20);
+        assert_eq!(calibration.sample_count, 3);
+
+        let mut even_samples = [40, 10, 20, 30];
+        let calibration = CpuFreqCalibration::from_samples(&mut even_samples);
+        assert_eq!(calibration.median, 25);
+        assert_eq!((calibration.min, calibration.max), (10, 40));
+    }
+
+    #[test]
+    fn cpu_freq_cache_round_trip() {
+        let path = std::env::temp_dir().join(format!("clocks_cpu_freq_cache_{}.txt", std::process::id()));
+        let path = path.to_str().unwrap();
+        let first = CpuFreqCalibration { median: 3_000_000_000, min: 2_999_000_000, max: 3_001_000_000, sample_count: 9 };
+        let second = CpuFreqCalibration { median: 2_000_000_000, min: 1_990_000_000, max: 2_010_000_000, sample_count: 5 };
+
+        save_cpu_freq_cache(path, "cpu a", &first).unwrap();
+        save_cpu_freq_cache(path, "cpu b", &second).unwrap();
+        save_cpu_freq_cache(path, "cpu a", &second).unwrap(); // replaces, does not duplicate
+
+        assert_eq!(load_cpu_freq_cache(path, "cpu a"), Some(second));
+        assert_eq!(load_cpu_freq_cache(path, "cpu b"), Some(second));
+        assert_eq!(load_cpu_freq_cache(path, "cpu c"), None);
+        assert_eq!(std::fs::read_to_string(path).unwrap().lines().count(), 2);
+        std::fs::remove_file(path).unwrap();
+    }
+}
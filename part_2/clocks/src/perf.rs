@@ -0,0 +1,195 @@
+// Hardware performance counters through Linux's perf_event_open.
+// All selected counters are opened as a single group so one read() returns a consistent snapshot.
+// Counters are commonly unavailable (containers, VMs, perf_event_paranoid), so opening never panics:
+// counters that fail to open are skipped and PerfCounters::open only errors when none could be opened.
+// On other platforms opening always fails with ErrorKind::Unsupported.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PerfCounter {
+    Cycles,
+    Instructions,
+    CacheReferences,
+    CacheMisses,
+    BranchInstructions,
+    BranchMisses,
+}
+
+pub const PERF_COUNTER_COUNT: usize = 6;
+
+// Indexed by `PerfCounter as usize`. Counters that were not opened stay 0.
+pub type PerfCounterValues = [u64; PERF_COUNTER_COUNT];
+
+impl PerfCounter {
+    pub const ALL: [PerfCounter; PERF_COUNTER_COUNT] = [
+        PerfCounter::Cycles, PerfCounter::Instructions,
+        PerfCounter::CacheReferences, PerfCounter::CacheMisses,
+        PerfCounter::BranchInstructions, PerfCounter::BranchMisses
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            PerfCounter::Cycles => "cycles",
+            PerfCounter::Instructions => "instructions",
+            PerfCounter::CacheReferences => "cache-references",
+            PerfCounter::CacheMisses => "cache-misses",
+            PerfCounter::BranchInstructions => "branches",
+            PerfCounter::BranchMisses => "branch-misses",
+        }
+    }
+}
+
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+pub struct PerfCounters {
+    group_fd: i32,
+    opened: Vec<(PerfCounter, i32)>, // In group order, which is the order values are read back in
+}
+
+impl PerfCounters {
+    pub fn counters(&self) -> impl Iterator<Item = PerfCounter> + '_ { self.opened.iter().map(|(counter, _)| *counter) }
+
+    pub fn has(&self, counter: PerfCounter) -> bool { self.opened.iter().any(|(c, _)| *c == counter) }
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    // perf_event_attr up to config2 (PERF_ATTR_SIZE_VER1), the kernel accepts older layouts
+    #[repr(C)]
+    #[derive(Default)]
+    pub struct PerfEventAttr {
+        pub type_: u32,
+        pub size: u32,
+        pub config: u64,
+        pub sample_period: u64,
+        pub sample_type: u64,
+        pub read_format: u64,
+        pub flags: u64,
+        pub wakeup_events: u32,
+        pub bp_type: u32,
+        pub config1: u64,
+        pub config2: u64,
+    }
+
+    pub const PERF_TYPE_HARDWARE: u32 = 0;
+    pub const PERF_FORMAT_GROUP: u64 = 1 << 3;
+    pub const FLAG_DISABLED: u64 = 1 << 0;
+    pub const FLAG_EXCLUDE_KERNEL: u64 = 1 << 5;
+    pub const FLAG_EXCLUDE_HV: u64 = 1 << 6;
+    pub const PERF_EVENT_IOC_ENABLE: u64 = 0x2400;
+    pub const PERF_EVENT_IOC_RESET: u64 = 0x2403;
+    pub const PERF_IOC_FLAG_GROUP: u64 = 1;
+}
+
+#[cfg(target_os = "linux")]
+impl PerfCounters {
+    pub fn open(counters: &[PerfCounter]) -> std::io::Result<PerfCounters> {
+        let mut perf_counters = PerfCounters { group_fd: -1, opened: Vec::new() };
+        let mut last_error = std::io::Error::new(std::io::ErrorKind::InvalidInput, "No perf counters were requested.");
+
+        for &counter in counters {
+            if perf_counters.has(counter) { continue; }
+
+            let config = match counter {
+                PerfCounter::Cycles => 0,
+                PerfCounter::Instructions => 1,
+                PerfCounter::CacheReferences => 2,
+                PerfCounter::CacheMisses => 3,
+                PerfCounter::BranchInstructions => 4,
+                PerfCounter::BranchMisses => 5,
+            };
+            let is_leader = perf_counters.group_fd == -1;
+            let attr = sys::PerfEventAttr {
+                type_: sys::PERF_TYPE_HARDWARE,
+                size: std::mem::size_of::<sys::PerfEventAttr>() as u32,
+                config,
+                read_format: sys::PERF_FORMAT_GROUP,
+                flags: sys::FLAG_EXCLUDE_KERNEL | sys::FLAG_EXCLUDE_HV | if is_leader { sys::FLAG_DISABLED } else { 0 },
+                ..Default::default()
+            };
+            // pid = 0 (this thread), cpu = -1 (any cpu), no flags
+            let fd = unsafe { libc::syscall(libc::SYS_perf_event_open, &attr as *const sys::PerfEventAttr, 0, -1, perf_counters.group_fd, 0 as libc::c_ulong) } as i32;
+            if fd < 0 {
+                last_error = std::io::Error::last_os_error();
+                continue;
+            }
+            if is_leader { perf_counters.group_fd = fd; }
+            perf_counters.opened.push((counter, fd));
+        }
+
+        if perf_counters.opened.is_empty() { return Err(last_error); }
+
+        unsafe {
+            libc::ioctl(perf_counters.group_fd, sys::PERF_EVENT_IOC_RESET as _, sys::PERF_IOC_FLAG_GROUP);
+            libc::ioctl(perf_counters.group_fd, sys::PERF_EVENT_IOC_ENABLE as _, sys::PERF_IOC_FLAG_GROUP);
+        }
+        Ok(perf_counters)
+    }
+
+    #[inline(always)]
+    pub fn read(&self) -> PerfCounterValues {
+        // PERF_FORMAT_GROUP layout: { u64 nr; u64 values[nr]; }
+        let mut buffer = [0u64; PERF_COUNTER_COUNT + 1];
+        let mut values: PerfCounterValues = [0; PERF_COUNTER_COUNT];
+        let bytes_read = unsafe { libc::read(self.group_fd, buffer.as_mut_ptr() as *mut libc::c_void, std::mem::size_of_val(&buffer)) };
+        if bytes_read > 0 {
+            let value_count = (buffer[0] as usize).min(self.opened.len());
+            for (i, (counter, _)) in self.opened[..value_count].iter().enumerate() {
+                values[*counter as usize] = buffer[i + 1];
+            }
+        }
+        values
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for PerfCounters {
+    fn drop(&mut self) {
+        for (_, fd) in self.opened.iter() {
+            unsafe { libc::close(*fd); }
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+impl PerfCounters {
+    pub fn open(_counters: &[PerfCounter]) -> std::io::Result<PerfCounters> {
+        Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "perf_event_open is only available on Linux."))
+    }
+
+    #[inline(always)]
+    pub fn read(&self) -> PerfCounterValues { [0; PERF_COUNTER_COUNT] }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counters_grow_across_work() {
+        // Denied in many containers and VMs, which is not a failure
+        let perf_counters = match PerfCounters::open(&PerfCounter::ALL) {
+            Ok(perf_counters) => perf_counters,
+            Err(e) => {
+                eprintln!("Skipping, perf counters are unavailable: {}", e);
+                return;
+            }
+        };
+        let start = perf_counters.read();
+        let sum: u64 = (0..100_000u64).map(std::hint::black_box).sum();
+        std::hint::black_box(sum);
+        let end = perf_counters.read();
+
+        for counter in PerfCounter::ALL {
+            let i = counter as usize;
+            assert!(end[i] >= start[i], "{} went backwards", counter.name());
+            if !perf_counters.has(counter) { assert_eq!((start[i], end[i]), (0, 0)); }
+        }
+        if perf_counters.has(PerfCounter::Instructions) {
+            assert!(end[PerfCounter::Instructions as usize] - start[PerfCounter::Instructions as usize] >= 100_000);
+        }
+    }
+
+    #[test]
+    fn opening_nothing_fails() {
+        assert!(PerfCounters::open(&[]).is_err());
+    }
+}
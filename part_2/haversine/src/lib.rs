@@ -11,7 +11,7 @@ fn radians(degrees: f64) -> f64 {
 
 // EarthRadius is generally expected to be 6372.8
 pub fn haversine(x0: f64, y0: f64, x1: f64, y1: f64, earth_radius: Option<f64>) -> f64 {
-    // time_function!();
+    time_sampled_function!(64);
     
     let lat1 = y0;
     let lat2 = y1;
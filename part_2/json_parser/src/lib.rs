@@ -263,7 +263,7 @@ pub struct JsonContext<'a> {
 
 impl Json<'_> {
 
-    pub fn get_root_context(&self) -> JsonContext {
+    pub fn get_root_context(&self) -> JsonContext<'_> {
         return JsonContext {
             parent: &self.root,
             child_index: 0
@@ -288,7 +288,7 @@ impl Json<'_> {
         }
     }
 
-    pub fn get_next_element(&self, context: &mut JsonContext) -> Option<(&[u8], &JsonValue)> {
+    pub fn get_next_element(&self, context: &mut JsonContext) -> Option<(&[u8], &JsonValue<'_>)> {
         return match context.parent {
             JsonValue::Array { start_index: _, end_index } => {
                 if context.child_index >= *end_index { None }
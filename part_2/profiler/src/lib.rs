@@ -1,8 +1,21 @@
-// NOTE: This profiler is absolutely not multi-threaded safe
+// NOTE: Each thread records into its own anchor table (see ThreadProfile), registered with the profiler
+//      the first time the thread records. Reports and teardown merge the tables of every registered
+//      thread, running or exited.
 // NOTE: The profiler is aggressively removed from non-profile feature builds to ensure
 //      that it does not affect the program in any way.
 
 pub use utils::Defer;
+pub use profiler_macros::profile;
+
+pub mod report;
+pub use report::{ProfileReport, ProfileReportEntry};
+
+pub mod live;
+
+#[cfg(feature = "tracing")]
+pub mod tracing_layer;
+#[cfg(feature = "tracing")]
+pub use tracing_layer::ProfilerLayer;
 
 #[cfg(feature = "profile")]
 pub use once_cell::sync::Lazy;
@@ -11,164 +24,1686 @@ pub use once_cell::sync::Lazy;
 pub use std::mem::drop;
 
 #[cfg(feature = "profile")]
-use clocks::{measure_cpu_freq, clocks_to_millisecs, read_cpu_timer};
+use std::cell::Cell;
+use std::alloc::{GlobalAlloc, Layout, System};
+#[cfg(feature = "profile")]
+use std::sync::{Arc, Mutex, MutexGuard};
+#[cfg(feature = "profile")]
+use std::time::Duration;
+#[cfg(feature = "profile")]
+use std::collections::{HashMap, VecDeque};
+#[cfg(feature = "profile")]
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+
+#[cfg(feature = "profile")]
+use clocks::{cached_cpu_freq, clocks_to_millisecs, cpu_info};
+
+#[cfg(feature = "profile")]
+pub use clocks::CpuTimerRead;
+
+#[cfg(feature = "profile")]
+pub use clocks::perf::{PerfCounter, PerfCounters, PerfCounterValues, PERF_COUNTER_COUNT};
+
+#[cfg(feature = "profile")]
+use clocks::faults::{read_max_rss_kb, read_page_faults, PageFaults};
+
+#[cfg(feature = "profile")]
+use utils::{ printable_freq, printable_large_num};
+
+#[cfg(feature = "profile")]
+use report::{json_escape, REPORT_PATH_ENV};
+
+#[cfg(feature = "profile")]
+use live::{LiveListener, LIVE_ADDRESS_ENV, DEFAULT_LIVE_INTERVAL, MIN_LIVE_INTERVAL};
+
+#[cfg(feature = "profile")]
+static EMPTY_TAG: &str = "";
+
+// Distinct runtime tags given their own anchor, further ones all share NAMED_OVERFLOW_TAG's
+#[cfg(feature = "profile")]
+pub const NAMED_ANCHOR_CAPACITY: usize = 4096;
+#[cfg(feature = "profile")]
+static NAMED_OVERFLOW_TAG: &str = "[other named blocks]";
+#[cfg(feature = "profile")]
+static NAMED_OVERFLOW_PROFILE_INDEX: Lazy<usize> = Lazy::new(|| {
+    eprintln!("WARNING: More than {} distinct runtime tags, further ones are merged into \"{}\".", NAMED_ANCHOR_CAPACITY, NAMED_OVERFLOW_TAG);
+    __GLOBAL_PROFILER__COUNTER__()
+});
+
+#[cfg(feature = "profile")]
+static ANCHOR_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+// NOTE: This is only used for the profiler macros. DO NOT use this for anything else. 
+#[cfg(feature = "profile")]
+#[allow(non_snake_case)]
+pub fn __GLOBAL_PROFILER__COUNTER__() -> usize {
+    // Counting starting at 1 is intended and not to be changed
+    // 0 is reserved for a default value
+    // Unbounded, thread tables grow to fit anchors registered after they were created (see ThreadProfile::anchor_mut)
+    ANCHOR_COUNTER.fetch_add(1, Ordering::Relaxed) + 1
+}
+
+// Anchors registered so far, the application anchor is not counted
+#[cfg(feature = "profile")]
+fn registered_anchor_count() -> usize { ANCHOR_COUNTER.load(Ordering::Relaxed) }
+
+// Length of the populated part of the anchor tables
+#[cfg(feature = "profile")]
+fn anchor_count() -> usize { registered_anchor_count() + 1 }
+
+#[cfg(feature = "profile")]
+#[derive(Clone, Copy, Default)]
+pub struct ProfileAnchor {
+    tag: &'static str,
+    elapsed_exclusive: u64, // Does not include children
+    elapsed_inclusive: u64, // Does include children
+    invocations: u64,
+    processed_byte_count: u64,
+    perf_exclusive: PerfCounterValues, // Hardware counter deltas, does not include children
+    alloc_exclusive: AllocCounts, // Allocations made by this anchor, does not include children
+    faults_exclusive: PageFaults, // Does not include children
+    faults_inclusive: PageFaults, // Includes children, counted like elapsed_inclusive
+    sample_period: u64, // Set by sampled blocks, which time 1 in sample_period invocations
+    sample_counter: u64, // Invocations of sampled blocks on this thread, timed or not
+    untimed_invocations: u64, // Included in invocations, but never opened a block
+    open_count: u32, // Blocks of this anchor currently open on this thread
+    max_depth: u32, // Most blocks of this anchor ever open at once, > 1 means it recursed
+    child_invocations: u64, // Blocks closed directly inside this anchor, used for overhead subtraction
+    descendant_invocations: u64, // Blocks closed anywhere inside this anchor, counted like elapsed_inclusive
+}
+
+#[cfg(feature = "profile")]
+impl ProfileAnchor {
+    fn merge(&mut self, other: &ProfileAnchor) {
+        if self.tag == EMPTY_TAG { self.tag = other.tag; }
+        self.elapsed_exclusive = self.elapsed_exclusive.wrapping_add(other.elapsed_exclusive);
+        self.elapsed_inclusive += other.elapsed_inclusive;
+        self.invocations += other.invocations;
+        self.processed_byte_count += other.processed_byte_count;
+        for (count, other_count) in self.perf_exclusive.iter_mut().zip(other.perf_exclusive.iter()) {
+            *count = count.wrapping_add(*other_count);
+        }
+        self.alloc_exclusive = self.alloc_exclusive.wrapping_add(&other.alloc_exclusive);
+        self.faults_exclusive = self.faults_exclusive.wrapping_add(&other.faults_exclusive);
+        self.faults_inclusive = self.faults_inclusive.wrapping_add(&other.faults_inclusive);
+        self.max_depth = self.max_depth.max(other.max_depth);
+        self.sample_period = self.sample_period.max(other.sample_period);
+        self.untimed_invocations += other.untimed_invocations;
+        self.child_invocations += other.child_invocations;
+        self.descendant_invocations += other.descendant_invocations;
+    }
+
+    // Extrapolated sampled children can take a parent's exclusive clocks slightly below zero
+    fn exclusive_clocks(&self) -> u64 {
+        if self.elapsed_exclusive > self.elapsed_inclusive { 0 } else { self.elapsed_exclusive }
+    }
+
+    // Invocations that opened a block, fewer than invocations for sampled blocks
+    fn timed_invocations(&self) -> u64 { self.invocations - self.untimed_invocations }
+
+    // Removes the calibrated block cost: every timed invocation measures self_overhead of its own bookkeeping,
+    // and every nested block adds block_overhead to the blocks around it (minus what it measures itself).
+    fn subtract_overhead(&mut self, overhead: &ProfilerOverhead) {
+        let own_overhead = self.timed_invocations() * overhead.self_overhead;
+        let child_overhead = self.child_invocations * overhead.block_overhead.saturating_sub(overhead.self_overhead);
+        self.elapsed_exclusive = self.elapsed_exclusive.saturating_sub(own_overhead + child_overhead);
+        self.elapsed_inclusive = self.elapsed_inclusive.saturating_sub(own_overhead + self.descendant_invocations * overhead.block_overhead);
+    }
+}
+
+// Running allocation totals of one thread. ProfileBlocks diff them the same way as perf counters.
+#[cfg(feature = "profile")]
+#[derive(Clone, Copy, Default)]
+struct AllocCounts {
+    count: u64,
+    allocated_bytes: u64,
+    freed_bytes: u64,
+}
+
+#[cfg(feature = "profile")]
+impl AllocCounts {
+    #[inline(always)]
+    fn wrapping_add(&self, other: &AllocCounts) -> AllocCounts {
+        AllocCounts {
+            count: self.count.wrapping_add(other.count),
+            allocated_bytes: self.allocated_bytes.wrapping_add(other.allocated_bytes),
+            freed_bytes: self.freed_bytes.wrapping_add(other.freed_bytes)
+        }
+    }
+
+    #[inline(always)]
+    fn wrapping_sub(&self, other: &AllocCounts) -> AllocCounts {
+        AllocCounts {
+            count: self.count.wrapping_sub(other.count),
+            allocated_bytes: self.allocated_bytes.wrapping_sub(other.allocated_bytes),
+            freed_bytes: self.freed_bytes.wrapping_sub(other.freed_bytes)
+        }
+    }
+}
+
+// Const initialized and without a destructor, so the allocator can touch it without allocating or re-entering
+#[cfg(feature = "profile")]
+thread_local! {
+    static THREAD_ALLOC_COUNTS: Cell<AllocCounts> = const { Cell::new(AllocCounts { count: 0, allocated_bytes: 0, freed_bytes: 0 }) };
+}
+
+#[cfg(feature = "profile")]
+static ALLOCATIONS_COUNTED: AtomicBool = AtomicBool::new(false);
+
+#[cfg(feature = "profile")]
+#[inline(always)]
+fn read_alloc_counts() -> AllocCounts { THREAD_ALLOC_COUNTS.try_with(Cell::get).unwrap_or_default() }
+
+#[cfg(feature = "profile")]
+#[inline(always)]
+fn count_allocation(count: u64, allocated_bytes: usize, freed_bytes: usize) {
+    let _ = THREAD_ALLOC_COUNTS.try_with(|alloc_counts| {
+        let counted = AllocCounts { count, allocated_bytes: allocated_bytes as u64, freed_bytes: freed_bytes as u64 };
+        alloc_counts.set(alloc_counts.get().wrapping_add(&counted));
+    });
+}
+
+// Opt-in global allocator that attributes allocations to the innermost open block, adding
+// Allocs/Allocated/Freed columns to the report. A realloc counts as one allocation of the new size
+// and a free of the old one. Without the profile feature it forwards to System and counts nothing.
+//      #[global_allocator]
+//      static ALLOCATOR: profiler::CountingAllocator = profiler::CountingAllocator;
+// part_2 installs it only with its alloc-counts feature.
+pub struct CountingAllocator;
+
+#[cfg(feature = "profile")]
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count_allocation(1, layout.size(), 0);
+        System.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        count_allocation(1, layout.size(), 0);
+        System.alloc_zeroed(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        count_allocation(0, 0, layout.size());
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        count_allocation(1, new_size, layout.size());
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[cfg(not(feature = "profile"))]
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 { System.alloc(layout) }
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 { System.alloc_zeroed(layout) }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) { System.dealloc(ptr, layout) }
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 { System.realloc(ptr, layout, new_size) }
+}
+
+// Cost of an empty block, measured at init with the timer read in use
+#[cfg(feature = "profile")]
+#[derive(Clone, Copy, Default)]
+struct ProfilerOverhead {
+    block_overhead: u64, // Clocks an empty nested block adds to its parent
+    self_overhead: u64, // Clocks an empty block reports for itself
+}
+
+// Bucket 0 holds 0 clocks, bucket n holds [2^(n-1), 2^n) clocks
+#[cfg(feature = "profile")]
+const BLOCK_STATS_BUCKET_COUNT: usize = 65;
+
+// Per-invocation distribution of an anchor's inclusive clocks, kept beside the anchor table
+// since the histogram would bloat every ProfileAnchor.
+#[cfg(feature = "profile")]
+#[derive(Clone, Copy)]
+struct BlockStats {
+    count: u64,
+    sum: u64,
+    min: u64,
+    max: u64,
+    histogram: [u64; BLOCK_STATS_BUCKET_COUNT]
+}
+
+#[cfg(feature = "profile")]
+impl Default for BlockStats {
+    fn default() -> Self {
+        BlockStats { count: 0, sum: 0, min: u64::MAX, max: 0, histogram: [0; BLOCK_STATS_BUCKET_COUNT] }
+    }
+}
+
+#[cfg(feature = "profile")]
+impl BlockStats {
+    #[inline(always)]
+    fn bucket(elapsed: u64) -> usize { (u64::BITS - elapsed.leading_zeros()) as usize }
+
+    #[inline(always)]
+    fn record(&mut self, elapsed: u64) {
+        self.count += 1;
+        self.sum += elapsed;
+        self.min = self.min.min(elapsed);
+        self.max = self.max.max(elapsed);
+        self.histogram[Self::bucket(elapsed)] += 1;
+    }
+
+    fn merge(&mut self, other: &BlockStats) {
+        self.count += other.count;
+        self.sum += other.sum;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        for (count, other_count) in self.histogram.iter_mut().zip(other.histogram.iter()) {
+            *count += other_count;
+        }
+    }
+
+    fn mean(&self) -> f64 { if self.count == 0 { 0.0 } else { self.sum as f64 / self.count as f64 } }
+
+    fn print(&self, tag: &str) {
+        let title_str = format!("{} ({}):", tag, printable_large_num(self.count));
+        println!("{:<40}{:<20}{:<20}{}", title_str, printable_large_num(self.min), format!("{:.1}", self.mean()), printable_large_num(self.max));
+
+        // Only the range between the fastest and slowest buckets, so gaps in bimodal timings stay visible
+        let first_bucket = Self::bucket(self.min);
+        let last_bucket = Self::bucket(self.max);
+        let largest_count = self.histogram[first_bucket..=last_bucket].iter().copied().max().unwrap_or(0).max(1);
+        for bucket in first_bucket..=last_bucket {
+            let count = self.histogram[bucket];
+            let range_str = if bucket == 0 { String::from("0") } else {
+                format!("{}-{}", printable_large_num(1 << (bucket - 1)), printable_large_num(((1u128 << bucket) - 1) as u64))
+            };
+            let bar = "#".repeat((count as f64 / largest_count as f64 * 40.0).ceil() as usize);
+            println!("  {:<38}{:<20}{:<10.2}{}", range_str, printable_large_num(count), count as f64 / self.count as f64 * 100.0, bar);
+        }
+    }
+}
+
+// A node is one anchor reached through one specific chain of parents, so the same anchor
+// called from two places is two nodes. Node 0 is the application root.
+#[cfg(feature = "profile")]
+#[derive(Clone, Copy, Default)]
+struct CallTreeNode {
+    profile_index: usize,
+    parent: usize,
+    elapsed_exclusive: u64,
+    elapsed_inclusive: u64,
+    invocations: u64,
+    recursive_invocations: u64, // Only set in collapsed trees, invocations folded in from deeper on the path
+}
+
+#[cfg(feature = "profile")]
+const NO_CALL_TREE_NODE: usize = usize::MAX;
+
+#[cfg(feature = "profile")]
+struct CallTree {
+    nodes: Vec<CallTreeNode>,
+    children: HashMap<(usize, usize), usize>, // (parent node, child anchor) -> child node
+    scope: usize
+}
+
+#[cfg(feature = "profile")]
+impl Default for CallTree {
+    fn default() -> Self {
+        CallTree { nodes: vec![CallTreeNode::default()], children: HashMap::new(), scope: 0 }
+    }
+}
+
+#[cfg(feature = "profile")]
+impl CallTree {
+    fn child(&mut self, parent: usize, profile_index: usize) -> usize {
+        let nodes = &mut self.nodes;
+        *self.children.entry((parent, profile_index)).or_insert_with(|| {
+            nodes.push(CallTreeNode { profile_index, parent, ..Default::default() });
+            nodes.len() - 1
+        })
+    }
+
+    // Adds every node of other below the matching path in self, root values are not merged
+    fn merge(&mut self, other: &CallTree) {
+        let mut node_map = vec![0; other.nodes.len()];
+        // Parents are always created before their children, so a single forward pass is enough
+        for (other_index, other_node) in other.nodes.iter().enumerate().skip(1) {
+            let node_index = self.child(node_map[other_node.parent], other_node.profile_index);
+            node_map[other_index] = node_index;
+            let node = &mut self.nodes[node_index];
+            node.elapsed_exclusive = node.elapsed_exclusive.wrapping_add(other_node.elapsed_exclusive);
+            node.elapsed_inclusive += other_node.elapsed_inclusive;
+            node.invocations += other_node.invocations;
+        }
+    }
+
+    // Folds every node whose anchor already appears further up its path into that ancestor, so recursion,
+    // direct or through other anchors, reads as a single node. Folded nodes add their exclusive time and
+    // invocations but not their inclusive time, which the ancestor's inclusive time already covers.
+    fn collapse_recursion(&self) -> CallTree {
+        let mut collapsed = CallTree::default();
+        collapsed.nodes[0] = self.nodes[0];
+        let mut node_map = vec![0; self.nodes.len()];
+        for (node_index, node) in self.nodes.iter().enumerate().skip(1) {
+            let mut ancestor = node.parent;
+            while ancestor != 0 && self.nodes[ancestor].profile_index != node.profile_index {
+                ancestor = self.nodes[ancestor].parent;
+            }
+            let is_recursive = ancestor != 0;
+            let collapsed_index = if is_recursive { node_map[ancestor] } else { collapsed.child(node_map[node.parent], node.profile_index) };
+            node_map[node_index] = collapsed_index;
+
+            let collapsed_node = &mut collapsed.nodes[collapsed_index];
+            collapsed_node.elapsed_exclusive = collapsed_node.elapsed_exclusive.wrapping_add(node.elapsed_exclusive);
+            collapsed_node.invocations += node.invocations;
+            if is_recursive {
+                collapsed_node.recursive_invocations += node.invocations;
+            } else {
+                collapsed_node.elapsed_inclusive += node.elapsed_inclusive;
+            }
+        }
+        collapsed
+    }
+
+    fn print(&self, profiles: &[ProfileAnchor]) {
+        let mut node_children = vec![Vec::new(); self.nodes.len()];
+        for (node_index, node) in self.nodes.iter().enumerate().skip(1) {
+            node_children[node.parent].push(node_index);
+        }
+
+        println!("\n{:<60}{:<25}{:<25}% Parent\n", "Call Tree (Invocations)", "Inclusive", "Exclusive");
+        let mut stack = vec![(0, 0)]; // (node, depth)
+        while let Some((node_index, depth)) = stack.pop() {
+            let node = &self.nodes[node_index];
+            let tag = if node_index == 0 { "Application" } else { profiles[node.profile_index].tag };
+            let invocations_str = if node.recursive_invocations == 0 { printable_large_num(node.invocations) } else {
+                format!("{}, {} recursive", printable_large_num(node.invocations), printable_large_num(node.recursive_invocations))
+            };
+            let title_str = format!("{:indent$}{} ({})", "", tag, invocations_str, indent = depth * 2);
+            let parent_inclusive = if node_index == 0 { node.elapsed_inclusive } else { self.nodes[node.parent].elapsed_inclusive };
+            let percent_parent = node.elapsed_inclusive as f64 / parent_inclusive as f64 * 100.0;
+            let exclusive = if node.elapsed_exclusive > node.elapsed_inclusive { 0 } else { node.elapsed_exclusive }; // See ProfileAnchor::exclusive_clocks
+            println!("{:<60}{:<25}{:<25}{:.2}", title_str, printable_large_num(node.elapsed_inclusive), printable_large_num(exclusive), percent_parent);
+            stack.extend(node_children[node_index].iter().rev().map(|child| (*child, depth + 1)));
+        }
+    }
+
+    // Brendan Gregg's folded stacks, one "root;child;grandchild <exclusive clocks>" line per node
+    fn write_folded(&self, path: &str, profiles: &[ProfileAnchor]) -> std::io::Result<()> {
+        use std::io::Write;
+
+        let mut writer = std::io::BufWriter::new(std::fs::File::create(path)?);
+        let mut stacks: Vec<String> = Vec::with_capacity(self.nodes.len());
+        for (node_index, node) in self.nodes.iter().enumerate() {
+            // ';' separates frames, so it cannot appear inside one
+            let stack = if node_index == 0 { String::from("Application") } else {
+                format!("{};{}", stacks[node.parent], profiles[node.profile_index].tag.replace(';', ","))
+            };
+            // Skip nodes with no exclusive time and any that wrapped below zero
+            if node.elapsed_exclusive > 0 && node.elapsed_exclusive <= node.elapsed_inclusive {
+                writeln!(writer, "{} {}", stack, node.elapsed_exclusive)?;
+            }
+            stacks.push(stack);
+        }
+        writer.flush()
+    }
+}
+
+// One completed block for the Chrome trace, kept in a per-thread ring buffer
+#[cfg(feature = "profile")]
+#[derive(Clone, Copy)]
+struct TraceEvent {
+    profile_index: usize,
+    start_stamp: u64,
+    elapsed: u64
+}
+
+#[cfg(feature = "profile")]
+#[derive(Default)]
+struct ThreadTrace {
+    thread_name: String,
+    events: VecDeque<TraceEvent>,
+    dropped_count: u64
+}
+
+#[cfg(feature = "profile")]
+impl ThreadTrace {
+    // trace_capacity is shared by every thread, exited ones included. Once it is spent a thread drops its
+    // own oldest event to make room, so threads that had no events yet record none.
+    fn record(&mut self, event: TraceEvent, trace_capacity: usize) {
+        // One atomic step, so threads recording at once cannot both take the last slot
+        let reserved = GLOBAL_PROFILER.trace_event_count.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |event_count| {
+            (event_count < trace_capacity).then_some(event_count + 1)
+        });
+        if reserved.is_err() {
+            self.dropped_count += 1;
+            if self.events.pop_front().is_none() { return; }
+        }
+        self.events.push_back(event);
+    }
+
+    // Gives the events back to the budget
+    fn clear(&mut self) {
+        GLOBAL_PROFILER.trace_event_count.fetch_sub(self.events.len(), Ordering::Relaxed);
+        self.events.clear();
+        self.dropped_count = 0;
+    }
+}
+
+#[cfg(feature = "profile")]
+pub const TRACE_PATH_ENV: &str = "PROFILER_TRACE";
+#[cfg(feature = "profile")]
+pub const DEFAULT_TRACE_CAPACITY: usize = 1_000_000;
+#[cfg(feature = "profile")]
+pub const FOLDED_PATH_ENV: &str = "PROFILER_FOLDED";
+#[cfg(feature = "profile")]
+pub const BLOCK_STATS_ENV: &str = "PROFILER_BLOCK_STATS";
+#[cfg(feature = "profile")]
+pub const PAGE_FAULTS_ENV: &str = "PROFILER_PAGE_FAULTS";
+#[cfg(feature = "profile")]
+pub const SUBTRACT_OVERHEAD_ENV: &str = "PROFILER_SUBTRACT_OVERHEAD";
+#[cfg(feature = "profile")]
+pub const TRACING_SPANS_ENV: &str = "PROFILER_TRACING_SPANS";
+// Target and name of the spans emitted for profile blocks, the block's tag is in their `tag` field
+#[cfg(feature = "tracing")]
+pub const TRACING_SPAN_TARGET: &str = "profiler";
+#[cfg(feature = "tracing")]
+pub const TRACING_SPAN_NAME: &str = "profile_block";
+#[cfg(feature = "profile")]
+static CALIBRATION_PROFILE_INDEX: Lazy<usize> = Lazy::new(__GLOBAL_PROFILER__COUNTER__);
+#[cfg(feature = "profile")]
+const OVERHEAD_CALIBRATION_BATCH_COUNT: usize = 16;
+#[cfg(feature = "profile")]
+const OVERHEAD_CALIBRATION_BATCH_SIZE: u64 = 1024;
+
+// Anchor table and scope stack of a single thread. Indexed by the same global anchor indices on every thread,
+// the table grows as the thread opens blocks of anchors registered after it was created.
+// Exited threads are folded together by name (see ThreadProfile::fold), keeping only what gets reported.
+#[cfg(feature = "profile")]
+#[derive(Default)]
+struct ThreadProfile {
+    thread_name: String, // "unnamed" for threads without a name, so they fold together on exit
+    thread_count: usize, // Threads folded into this table
+    profiles: Vec<ProfileAnchor>,
+    scope: usize,
+    perf_counters: Option<PerfCounters>, // perf_event counts are per thread, so each thread opens its own
+    teardown_start_perf: PerfCounterValues,
+    teardown_start_alloc: AllocCounts,
+    teardown_start_faults: Option<PageFaults>,
+    call_tree: CallTree,
+    trace: ThreadTrace, // Handed to exited_traces on exit rather than folded, each thread keeps its own track
+    block_count: u64, // Blocks closed on this thread
+    teardown_start_block_count: u64,
+    named_anchors: HashMap<String, (&'static str, usize)>, // Thread-local cache of GLOBAL_PROFILER.named_anchors
+    block_stats: Vec<BlockStats>, // Indexed like profiles, left empty until block stats are enabled
+    session_generation: u64, // Recorded data is dropped when this falls behind GLOBAL_PROFILER's
+    live_table: Option<Arc<Mutex<LiveTable>>>, // Registered in GLOBAL_PROFILER.live_tables on first refresh
+    live_refresh_stamp: u64
+}
+
+// Copy of a running thread's anchor table, refreshed by the thread itself as blocks close so the
+// live publisher can read it. Threads hand their tables to exited_thread_profiles instead on exit.
+#[cfg(feature = "profile")]
+#[derive(Default)]
+struct LiveTable {
+    profiles: Vec<ProfileAnchor>,
+    session_generation: u64
+}
+
+#[cfg(feature = "profile")]
+impl ThreadProfile {
+    fn new() -> Self {
+        let current_thread = std::thread::current();
+        let thread_name = String::from(current_thread.name().unwrap_or("unnamed"));
+        let mut profiles = vec![ProfileAnchor::default(); anchor_count()];
+        profiles[0].tag = "Application";
+        let perf_counter_selection = GLOBAL_PROFILER.perf_counter_selection.lock().unwrap();
+        let perf_counters = if perf_counter_selection.is_empty() { None } else { PerfCounters::open(&perf_counter_selection).ok() };
+        let trace = ThreadTrace { thread_name: thread_name.clone(), ..Default::default() };
+        ThreadProfile { thread_name, thread_count: 1, profiles, perf_counters, trace, ..Default::default() }
+    }
+
+    #[inline(always)]
+    fn anchor_mut(&mut self, profile_index: usize) -> &mut ProfileAnchor {
+        if profile_index >= self.profiles.len() { self.grow(profile_index); }
+        &mut self.profiles[profile_index]
+    }
+
+    #[cold]
+    fn grow(&mut self, profile_index: usize) {
+        self.profiles.resize(anchor_count().max(profile_index + 1), ProfileAnchor::default());
+    }
+
+    // Adds an exited thread's anchors, call tree and block stats to this table
+    fn fold(&mut self, other: &ThreadProfile) {
+        if other.profiles.len() > self.profiles.len() { self.profiles.resize(other.profiles.len(), ProfileAnchor::default()); }
+        self.profiles[0].elapsed_exclusive = self.profiles[0].elapsed_exclusive.wrapping_add(other.profiles[0].elapsed_exclusive);
+        merge_anchor_table(&mut self.profiles, &other.profiles);
+        self.call_tree.merge(&other.call_tree);
+        if other.block_stats.len() > self.block_stats.len() { self.block_stats.resize(other.block_stats.len(), BlockStats::default()); }
+        for (stats, other_stats) in self.block_stats.iter_mut().zip(other.block_stats.iter()) {
+            stats.merge(other_stats);
+        }
+        self.thread_count += other.thread_count;
+    }
+
+    // Drops everything recorded, anchor tags included so untouched anchors leave the report
+    fn reset_session(&mut self, session_generation: u64) {
+        self.profiles.fill(ProfileAnchor::default());
+        self.profiles[0].tag = "Application";
+        self.call_tree = CallTree::default();
+        self.trace.clear();
+        self.block_count = 0;
+        self.block_stats.clear();
+        self.session_generation = session_generation;
+    }
+
+    // Skips the refresh rather than wait while the publisher is reading the copy
+    fn refresh_live_table(&mut self, now: u64, live_refresh_clocks: u64) {
+        if now.wrapping_sub(self.live_refresh_stamp) < live_refresh_clocks { return; }
+        self.live_refresh_stamp = now;
+        let live_table = self.live_table.get_or_insert_with(|| {
+            let live_table = Arc::default();
+            GLOBAL_PROFILER.live_tables.lock().unwrap().push(Arc::clone(&live_table));
+            live_table
+        });
+        if let Ok(mut live_table) = live_table.try_lock() {
+            live_table.profiles.clear();
+            live_table.profiles.extend_from_slice(&self.profiles);
+            live_table.session_generation = self.session_generation;
+        }
+    }
+
+    #[inline(always)]
+    fn read_perf_counters(&self) -> Option<PerfCounterValues> {
+        self.perf_counters.as_ref().map(PerfCounters::read)
+    }
+}
+
+// The thread's table, shared with GLOBAL_PROFILER.running_thread_profiles so reports can read it while
+// the thread runs. Folded into exited_thread_profiles when the thread exits.
+#[cfg(feature = "profile")]
+struct ThreadProfileCell(Arc<Mutex<ThreadProfile>>);
+
+#[cfg(feature = "profile")]
+impl ThreadProfileCell {
+    fn register() -> Self {
+        let thread_profile = Arc::new(Mutex::new(ThreadProfile::new()));
+        GLOBAL_PROFILER.running_thread_profiles.lock().unwrap().push(Arc::clone(&thread_profile));
+        ThreadProfileCell(thread_profile)
+    }
+}
+
+#[cfg(feature = "profile")]
+impl Drop for ThreadProfileCell {
+    fn drop(&mut self) {
+        // The table leaves running_thread_profiles and joins exited_thread_profiles under one lock, so
+        // reports never miss it while the thread is between the two
+        let Ok(mut running_thread_profiles) = GLOBAL_PROFILER.running_thread_profiles.lock() else { return; };
+        running_thread_profiles.retain(|thread_profile| !Arc::ptr_eq(thread_profile, &self.0));
+        let Ok(mut thread_profile) = self.0.lock().map(|mut thread_profile| std::mem::take(&mut *thread_profile)) else { return; };
+        if let Some(live_table) = thread_profile.live_table.take() {
+            if let Ok(mut live_tables) = GLOBAL_PROFILER.live_tables.lock() {
+                live_tables.retain(|table| !Arc::ptr_eq(table, &live_table));
+            }
+        }
+        // Recorded before the last reset
+        if thread_profile.session_generation != GLOBAL_PROFILER.session_generation.load(Ordering::Relaxed) {
+            thread_profile.trace.clear();
+            return;
+        }
+
+        if !thread_profile.trace.events.is_empty() || thread_profile.trace.dropped_count > 0 {
+            if let Ok(mut exited_traces) = GLOBAL_PROFILER.exited_traces.lock() {
+                exited_traces.push(std::mem::take(&mut thread_profile.trace));
+            }
+        }
+        if let Ok(mut exited_thread_profiles) = GLOBAL_PROFILER.exited_thread_profiles.lock() {
+            match exited_thread_profiles.iter_mut().find(|exited| exited.thread_name == thread_profile.thread_name) {
+                Some(exited) => exited.fold(&thread_profile),
+                None => exited_thread_profiles.push(ThreadProfile {
+                    thread_name: thread_profile.thread_name,
+                    thread_count: thread_profile.thread_count,
+                    profiles: thread_profile.profiles,
+                    call_tree: thread_profile.call_tree,
+                    block_stats: thread_profile.block_stats,
+                    session_generation: thread_profile.session_generation,
+                    ..Default::default()
+                })
+            }
+        }
+    }
+}
+
+#[cfg(feature = "profile")]
+thread_local! {
+    static THREAD_PROFILE: ThreadProfileCell = ThreadProfileCell::register();
+}
+
+// Returns None once the thread's table has been handed over (blocks dropped during thread exit).
+#[cfg(feature = "profile")]
+#[inline(always)]
+fn with_thread_profile<R>(f: impl FnOnce(&mut ThreadProfile) -> R) -> Option<R> {
+    // Only contended while a report reads the table, these closures never re-enter
+    THREAD_PROFILE.try_with(|cell| f(&mut cell.0.lock().unwrap())).ok()
+}
+
+// Identifies the calling thread's table in running_thread_profiles, registering it if needed. Must be
+// called before locking running_thread_profiles.
+#[cfg(feature = "profile")]
+fn current_thread_profile() -> Option<*const Mutex<ThreadProfile>> {
+    THREAD_PROFILE.try_with(|cell| Arc::as_ptr(&cell.0)).ok()
+}
+
+// Locks every running thread's table, the calling thread's first. Blocks closing on those threads wait
+// until the guards are dropped.
+#[cfg(feature = "profile")]
+fn lock_thread_profiles(thread_profiles: &[Arc<Mutex<ThreadProfile>>], current_thread_profile: Option<*const Mutex<ThreadProfile>>) -> Vec<MutexGuard<'_, ThreadProfile>> {
+    let mut thread_profiles: Vec<&Arc<Mutex<ThreadProfile>>> = thread_profiles.iter().collect();
+    thread_profiles.sort_by_key(|thread_profile| Some(Arc::as_ptr(thread_profile)) != current_thread_profile);
+    thread_profiles.into_iter().map(|thread_profile| thread_profile.lock().unwrap()).collect()
+}
+
+#[cfg(feature = "profile")]
+pub struct Profiler {
+    creation_stamp: AtomicU64,
+    recording: AtomicBool, // Blocks opened while false are not recorded
+    session_start_stamp: AtomicU64, // When recording last started
+    session_clocks: AtomicU64, // Clocks recorded before the last stop
+    session_generation: AtomicU64, // Bumped by reset, other threads reset their tables when they see it
+    teardown_start_stamp: AtomicU64,
+    timer_read: AtomicU8, // How ProfileBlocks read the CPU timer, index into CpuTimerRead::ALL
+    perf_counter_selection: Mutex<Vec<PerfCounter>>, // Opened by each thread when its table is created
+    call_tree_enabled: AtomicBool, // Recording, the tree is only printed when print_call_tree is set
+    print_call_tree: AtomicBool,
+    folded_path: Mutex<Option<String>>,
+    trace_capacity: AtomicUsize, // Events kept across all threads, 0 when not tracing
+    trace_event_count: AtomicUsize,
+    trace_path: Mutex<Option<String>>,
+    block_stats_enabled: AtomicBool,
+    page_faults_enabled: AtomicBool,
+    block_overhead: AtomicU64,
+    self_overhead: AtomicU64,
+    subtract_overhead: AtomicBool,
+    #[cfg(feature = "tracing")]
+    tracing_spans_enabled: AtomicBool,
+    live_refresh_clocks: AtomicU64, // How often threads refresh their live table, 0 when not publishing
+    live_tables: Mutex<Vec<Arc<Mutex<LiveTable>>>>, // Of running threads
+    live_listeners: Mutex<Vec<LiveListener>>, // Served by a single publisher thread, started with the first
+    live_interval_micros: AtomicU64,
+    named_anchors: Mutex<Option<HashMap<String, (&'static str, usize)>>>, // Runtime tag -> (interned tag, anchor index)
+    running_thread_profiles: Mutex<Vec<Arc<Mutex<ThreadProfile>>>>, // Locked before exited_thread_profiles and any thread's table
+    exited_thread_profiles: Mutex<Vec<ThreadProfile>>, // One per thread name
+    exited_traces: Mutex<Vec<ThreadTrace>>
+}
+#[cfg(feature = "profile")]
+pub static GLOBAL_PROFILER: Profiler = Profiler{ 
+    creation_stamp: AtomicU64::new(0),
+    recording: AtomicBool::new(true),
+    session_start_stamp: AtomicU64::new(0),
+    session_clocks: AtomicU64::new(0),
+    session_generation: AtomicU64::new(0),
+    teardown_start_stamp: AtomicU64::new(0),
+    timer_read: AtomicU8::new(0),
+    perf_counter_selection: Mutex::new(Vec::new()),
+    call_tree_enabled: AtomicBool::new(false),
+    print_call_tree: AtomicBool::new(false),
+    folded_path: Mutex::new(None),
+    trace_capacity: AtomicUsize::new(0),
+    trace_event_count: AtomicUsize::new(0),
+    trace_path: Mutex::new(None),
+    block_stats_enabled: AtomicBool::new(false),
+    page_faults_enabled: AtomicBool::new(false),
+    block_overhead: AtomicU64::new(0),
+    self_overhead: AtomicU64::new(0),
+    subtract_overhead: AtomicBool::new(false),
+    #[cfg(feature = "tracing")]
+    tracing_spans_enabled: AtomicBool::new(false),
+    live_refresh_clocks: AtomicU64::new(0),
+    live_tables: Mutex::new(Vec::new()),
+    live_listeners: Mutex::new(Vec::new()),
+    live_interval_micros: AtomicU64::new(0),
+    named_anchors: Mutex::new(None),
+    running_thread_profiles: Mutex::new(Vec::new()),
+    exited_thread_profiles: Mutex::new(Vec::new()),
+    exited_traces: Mutex::new(Vec::new())
+};
+
+// Recursion model, for direct (A -> A) and indirect (A -> B -> A) recursion alike:
+// - Every block counts as an invocation and adds its own exclusive time, so exclusive times still sum to
+//   the profiled total.
+// - Inclusive time is restored from the value saved when the block opened, so only the outermost open
+//   block of an anchor decides it and time spent in nested instances is not counted twice.
+// - max_depth records how many blocks of one anchor were open at once on a thread.
+#[cfg(feature = "profile")]
+pub struct ProfileBlock {
+    tag: &'static str,
+    creation_stamp: u64,
+    old_elapsed_inclusive: u64,
+    old_descendant_invocations: u64,
+    old_faults_inclusive: PageFaults,
+    start_block_count: u64,
+    parent_index: usize,
+    profile_index: usize,
+    perf_start: PerfCounterValues,
+    alloc_start: AllocCounts,
+    faults_start: Option<PageFaults>, // None when page faults are not being sampled
+    call_tree_parent: usize, // NO_CALL_TREE_NODE when the call tree is not being recorded
+    sample_period: u64, // Clocks are scaled by this, 1 unless the block is sampled
+    #[cfg(feature = "tracing")]
+    tracing_span: Option<tracing::Span> // Entered while the block is open, None unless tracing spans are enabled
+}
+
+#[cfg(feature = "profile")]
+impl ProfileBlock {
+    pub fn new(tag: &'static str, profile_index: usize, byte_count: u64) -> Self {
+        if !GLOBAL_PROFILER.recording.load(Ordering::Relaxed) { return ProfileBlock::paused(); }
+        let call_tree_enabled = GLOBAL_PROFILER.call_tree_enabled.load(Ordering::Relaxed);
+        let (parent_index, old_elapsed_inclusive, old_descendant_invocations, old_faults_inclusive, start_block_count, perf_start, call_tree_parent) = with_thread_profile(|thread_profile| {
+            // Only outermost blocks pick up a reset, so no open block straddles it on this thread
+            if thread_profile.scope == 0 {
+                let session_generation = GLOBAL_PROFILER.session_generation.load(Ordering::Relaxed);
+                if thread_profile.session_generation != session_generation { thread_profile.reset_session(session_generation); }
+            }
+            let parent_index = thread_profile.scope;
+            thread_profile.scope = profile_index;
+            let call_tree_parent = if call_tree_enabled {
+                let call_tree = &mut thread_profile.call_tree;
+                let call_tree_parent = call_tree.scope;
+                call_tree.scope = call_tree.child(call_tree_parent, profile_index);
+                call_tree_parent
+            } else { NO_CALL_TREE_NODE };
+            let perf_start = thread_profile.read_perf_counters().unwrap_or_default();
+            let profile_anchor = thread_profile.anchor_mut(profile_index);
+            profile_anchor.processed_byte_count += byte_count;
+            profile_anchor.open_count += 1;
+            profile_anchor.max_depth = profile_anchor.max_depth.max(profile_anchor.open_count);
+            (parent_index, profile_anchor.elapsed_inclusive, profile_anchor.descendant_invocations, profile_anchor.faults_inclusive, thread_profile.block_count, perf_start, call_tree_parent)
+        }).unwrap_or((0, 0, 0, PageFaults::default(), 0, [0; PERF_COUNTER_COUNT], NO_CALL_TREE_NODE));
+        let faults_start = if GLOBAL_PROFILER.page_faults_enabled.load(Ordering::Relaxed) { read_page_faults() } else { None };
+        #[cfg(feature = "tracing")]
+        let tracing_span = GLOBAL_PROFILER.tracing_spans_enabled.load(Ordering::Relaxed).then(|| enter_tracing_span(tag, byte_count));
+        ProfileBlock {
+            tag,
+            alloc_start: read_alloc_counts(),
+            faults_start,
+            creation_stamp: GLOBAL_PROFILER.timer_read().read(),
+            old_elapsed_inclusive,
+            old_descendant_invocations,
+            old_faults_inclusive,
+            start_block_count,
+            parent_index,
+            profile_index,
+            perf_start,
+            call_tree_parent,
+            sample_period: 1,
+            #[cfg(feature = "tracing")]
+            tracing_span
+        }
+    }
+
+    // Stands in for blocks opened while the profiler is stopped, dropping it does nothing
+    #[inline(always)]
+    fn paused() -> Self {
+        ProfileBlock {
+            tag: EMPTY_TAG, creation_stamp: 0, old_elapsed_inclusive: 0, old_descendant_invocations: 0, old_faults_inclusive: PageFaults::default(), start_block_count: 0,
+            parent_index: 0, profile_index: 0, perf_start: [0; PERF_COUNTER_COUNT], alloc_start: AllocCounts::default(), faults_start: None, call_tree_parent: NO_CALL_TREE_NODE, sample_period: 1,
+            #[cfg(feature = "tracing")]
+            tracing_span: None
+        }
+    }
+}
+
+#[cfg(feature = "profile")]
+impl ProfileBlock {
+    // Blocks with tags only known at runtime. The first NAMED_ANCHOR_CAPACITY distinct tags each get their
+    // own anchor, interned for the rest of the program. Later tags share a single "[other named blocks]"
+    // anchor, so per-iteration labels are only told apart for that many iterations.
+    // Costs a hash lookup per block on top of ProfileBlock::new.
+    pub fn named(tag: &str, byte_count: u64) -> Self {
+        if !GLOBAL_PROFILER.recording.load(Ordering::Relaxed) { return ProfileBlock::paused(); }
+        let (tag, profile_index) = named_anchor(tag);
+        ProfileBlock::new(tag, profile_index, byte_count)
+    }
+
+    // Like named, but the tag is only formatted while recording
+    pub fn named_args(tag: std::fmt::Arguments, byte_count: u64) -> Self {
+        if !GLOBAL_PROFILER.recording.load(Ordering::Relaxed) { return ProfileBlock::paused(); }
+        ProfileBlock::named(&tag.to_string(), byte_count)
+    }
+}
+
+#[cfg(feature = "profile")]
+impl ProfileBlock {
+    // For blocks too hot to time every invocation. Every invocation is counted, but only 1 in
+    // sample_period opens a block, whose clocks are scaled by sample_period to extrapolate the
+    // untimed ones. The first invocation is always timed. Only clocks are extrapolated: allocations,
+    // page faults and perf counts of untimed invocations stay with the enclosing block.
+    #[inline(always)]
+    pub fn sampled(tag: &'static str, profile_index: usize, byte_count: u64, sample_period: u64) -> Option<Self> {
+        if !GLOBAL_PROFILER.recording.load(Ordering::Relaxed) { return None; }
+        let sample_period = sample_period.max(1);
+        let call_tree_enabled = GLOBAL_PROFILER.call_tree_enabled.load(Ordering::Relaxed);
+        let timed = with_thread_profile(|thread_profile| {
+            let profile_anchor = thread_profile.anchor_mut(profile_index);
+            let timed = profile_anchor.sample_counter % sample_period == 0;
+            profile_anchor.sample_counter += 1;
+            profile_anchor.sample_period = sample_period;
+            if !timed {
+                profile_anchor.invocations += 1;
+                profile_anchor.untimed_invocations += 1;
+                profile_anchor.processed_byte_count += byte_count;
+                if call_tree_enabled {
+                    let call_tree = &mut thread_profile.call_tree;
+                    let node = call_tree.child(call_tree.scope, profile_index);
+                    call_tree.nodes[node].invocations += 1;
+                }
+            }
+            timed
+        })?;
+        timed.then(|| {
+            let mut block = ProfileBlock::new(tag, profile_index, byte_count);
+            block.sample_period = sample_period;
+            block
+        })
+    }
+}
+
+// Interned tag and anchor index for a runtime tag, cached per thread in front of the global table.
+// Tags past NAMED_ANCHOR_CAPACITY are not cached, so the cache stays as bounded as the global table.
+#[cfg(feature = "profile")]
+fn named_anchor(tag: &str) -> (&'static str, usize) {
+    with_thread_profile(|thread_profile| {
+        if let Some(named_anchor) = thread_profile.named_anchors.get(tag) { return *named_anchor; }
+        let named_anchor = GLOBAL_PROFILER.named_anchor(tag);
+        if !std::ptr::eq(named_anchor.0, NAMED_OVERFLOW_TAG) {
+            thread_profile.named_anchors.insert(String::from(tag), named_anchor);
+        }
+        named_anchor
+    }).unwrap_or_else(|| GLOBAL_PROFILER.named_anchor(tag))
+}
+
+// Entered by hand rather than through a guard so ProfileBlock stays Send
+#[cfg(all(feature = "profile", feature = "tracing"))]
+fn enter_tracing_span(tag: &'static str, byte_count: u64) -> tracing::Span {
+    let span = tracing::info_span!(target: TRACING_SPAN_TARGET, TRACING_SPAN_NAME, tag, bytes = byte_count);
+    span.with_subscriber(|(id, dispatch)| dispatch.enter(id));
+    span
+}
+
+#[cfg(feature = "profile")]
+impl Drop for ProfileBlock {
+    fn drop(&mut self) {
+        if self.profile_index == 0 { return; } // Opened while stopped
+        let elapsed: u64 = GLOBAL_PROFILER.timer_read().read() - self.creation_stamp;
+        #[cfg(feature = "tracing")]
+        if let Some(span) = self.tracing_span.take() {
+            span.with_subscriber(|(id, dispatch)| dispatch.exit(id));
+        }
+        let alloc_elapsed = read_alloc_counts().wrapping_sub(&self.alloc_start);
+        let faults_elapsed = self.faults_start.and_then(|faults_start| Some(read_page_faults()?.wrapping_sub(&faults_start))).unwrap_or_default();
+        if alloc_elapsed.count > 0 || alloc_elapsed.freed_bytes > 0 { ALLOCATIONS_COUNTED.store(true, Ordering::Relaxed); }
+        let trace_capacity = GLOBAL_PROFILER.trace_capacity.load(Ordering::Relaxed);
+        let block_stats_enabled = GLOBAL_PROFILER.block_stats_enabled.load(Ordering::Relaxed);
+        let live_refresh_clocks = GLOBAL_PROFILER.live_refresh_clocks.load(Ordering::Relaxed);
+        // Traces and block stats keep what was measured, the anchors get the sampling estimate. Untimed
+        // invocations did not pay for the timing inside the block, so that is taken off their estimate.
+        let untimed_estimate = elapsed.saturating_sub(GLOBAL_PROFILER.self_overhead.load(Ordering::Relaxed));
+        let extrapolated = elapsed + untimed_estimate * (self.sample_period - 1);
+        with_thread_profile(|thread_profile| {
+            let perf_end = thread_profile.read_perf_counters();
+            thread_profile.scope = self.parent_index;
+            let descendant_invocations = thread_profile.block_count - self.start_block_count;
+            thread_profile.block_count += 1;
+
+            let parent_profile = &mut thread_profile.profiles[self.parent_index];
+            parent_profile.elapsed_exclusive = parent_profile.elapsed_exclusive.wrapping_sub(extrapolated);
+            parent_profile.child_invocations += 1;
+            parent_profile.alloc_exclusive = parent_profile.alloc_exclusive.wrapping_sub(&alloc_elapsed);
+            parent_profile.faults_exclusive = parent_profile.faults_exclusive.wrapping_sub(&faults_elapsed);
+            if let Some(perf_end) = perf_end {
+                for (i, end) in perf_end.iter().enumerate() {
+                    parent_profile.perf_exclusive[i] = parent_profile.perf_exclusive[i].wrapping_sub(end.wrapping_sub(self.perf_start[i]));
+                }
+            }
+
+            let profile = thread_profile.anchor_mut(self.profile_index); // The teardown block was not opened through new
+            profile.elapsed_exclusive = profile.elapsed_exclusive.wrapping_add(extrapolated);
+            profile.elapsed_inclusive = self.old_elapsed_inclusive + extrapolated;
+            profile.descendant_invocations = self.old_descendant_invocations + descendant_invocations;
+            profile.alloc_exclusive = profile.alloc_exclusive.wrapping_add(&alloc_elapsed);
+            profile.faults_exclusive = profile.faults_exclusive.wrapping_add(&faults_elapsed);
+            profile.faults_inclusive = self.old_faults_inclusive.wrapping_add(&faults_elapsed);
+            profile.invocations += 1;
+            profile.open_count = profile.open_count.saturating_sub(1); // Saturating as a reset may have zeroed it
+            profile.tag = self.tag;
+            if let Some(perf_end) = perf_end {
+                for (i, end) in perf_end.iter().enumerate() {
+                    profile.perf_exclusive[i] = profile.perf_exclusive[i].wrapping_add(end.wrapping_sub(self.perf_start[i]));
+                }
+            }
+
+            if self.call_tree_parent != NO_CALL_TREE_NODE {
+                let call_tree = &mut thread_profile.call_tree;
+                let node = &mut call_tree.nodes[call_tree.scope];
+                node.elapsed_exclusive = node.elapsed_exclusive.wrapping_add(extrapolated);
+                node.elapsed_inclusive += extrapolated;
+                node.invocations += 1; // Untimed sampled invocations are counted when they are skipped
+                let parent_node = &mut call_tree.nodes[self.call_tree_parent];
+                parent_node.elapsed_exclusive = parent_node.elapsed_exclusive.wrapping_sub(extrapolated);
+                call_tree.scope = self.call_tree_parent;
+            }
+
+            if trace_capacity > 0 {
+                thread_profile.trace.record(TraceEvent { profile_index: self.profile_index, start_stamp: self.creation_stamp, elapsed }, trace_capacity);
+            }
+
+            if block_stats_enabled {
+                if self.profile_index >= thread_profile.block_stats.len() { thread_profile.block_stats.resize(thread_profile.profiles.len(), BlockStats::default()); }
+                thread_profile.block_stats[self.profile_index].record(elapsed);
+            }
+
+            if live_refresh_clocks > 0 {
+                thread_profile.refresh_live_table(self.creation_stamp + elapsed, live_refresh_clocks);
+            }
+        });
+    }
+}
+
+#[cfg(feature = "profile")]
+impl Profiler {
+    pub fn init(&self) {
+        self.calibrate_overhead();
+        if std::env::var_os(SUBTRACT_OVERHEAD_ENV).is_some() {
+            self.enable_overhead_subtraction();
+        }
+        if let Ok(trace_path) = std::env::var(TRACE_PATH_ENV) {
+            self.enable_trace(&trace_path, DEFAULT_TRACE_CAPACITY);
+        }
+        if let Ok(folded_path) = std::env::var(FOLDED_PATH_ENV) {
+            self.enable_folded_stacks(&folded_path);
+        }
+        if std::env::var_os(BLOCK_STATS_ENV).is_some() {
+            self.enable_block_stats();
+        }
+        if std::env::var_os(PAGE_FAULTS_ENV).is_some() {
+            self.enable_page_faults();
+        }
+        if std::env::var_os(TRACING_SPANS_ENV).is_some() {
+            self.enable_tracing_spans();
+        }
+        if let Ok(live_address) = std::env::var(LIVE_ADDRESS_ENV) {
+            if let Err(error) = self.publish_live(&live_address, DEFAULT_LIVE_INTERVAL) {
+                eprintln!("WARNING: Failed to publish live profiler results on {}: {}", live_address, error);
+            }
+        }
+        let creation_stamp = self.timer_read().read();
+        self.creation_stamp.store(creation_stamp, Ordering::Relaxed);
+        self.session_start_stamp.store(creation_stamp, Ordering::Relaxed);
+    }
+
+    // Sessions: the profiler records from init until stop(), start() picks back up where it left off and
+    // reset() throws away everything recorded so far. Percentages and totals cover only recorded time.
+    // Blocks still open when stop() is called are recorded in full, including the time spent stopped.
+    // Blocks opened while stopped cost a single relaxed atomic load.
+    pub fn start(&self) {
+        if !self.recording.load(Ordering::Relaxed) {
+            self.session_start_stamp.store(self.timer_read().read(), Ordering::Relaxed);
+            self.recording.store(true, Ordering::Relaxed);
+        }
+    }
+
+    pub fn stop(&self) {
+        if self.recording.swap(false, Ordering::Relaxed) {
+            let elapsed = self.timer_read().read() - self.session_start_stamp.load(Ordering::Relaxed);
+            self.session_clocks.fetch_add(elapsed, Ordering::Relaxed);
+        }
+    }
+
+    pub fn is_recording(&self) -> bool { self.recording.load(Ordering::Relaxed) }
+
+    // Clears exited threads right away. Running threads, the calling one included, drop their data
+    // at their next outermost block, so blocks open across the reset are closed against the old
+    // session. Until then their tables are left out of report().
+    pub fn reset(&self) {
+        self.session_generation.fetch_add(1, Ordering::Relaxed);
+        self.exited_thread_profiles.lock().unwrap().clear();
+        self.exited_traces.lock().unwrap().drain(..).for_each(|mut trace| trace.clear());
+        with_thread_profile(|thread_profile| self.sync_session(thread_profile));
+        self.teardown_start_stamp.store(0, Ordering::Relaxed);
+        self.session_clocks.store(0, Ordering::Relaxed);
+        self.session_start_stamp.store(self.timer_read().read(), Ordering::Relaxed);
+    }
+
+    // Catches the calling thread up with a reset made on another thread
+    fn sync_session(&self, thread_profile: &mut ThreadProfile) {
+        let session_generation = self.session_generation.load(Ordering::Relaxed);
+        if thread_profile.session_generation != session_generation && thread_profile.scope == 0 { thread_profile.reset_session(session_generation); }
+    }
+
+    // Running threads, the calling one first, then exited threads, skipping tables recorded before the last reset
+    fn session_thread_profiles<'a>(&self, running_thread_profiles: &'a [MutexGuard<ThreadProfile>], exited_thread_profiles: &'a [ThreadProfile]) -> Vec<&'a ThreadProfile> {
+        let session_generation = self.session_generation.load(Ordering::Relaxed);
+        running_thread_profiles.iter().map(|thread_profile| &**thread_profile)
+            .chain(exited_thread_profiles.iter())
+            .filter(|thread| thread.session_generation == session_generation)
+            .collect()
+    }
+
+    // Clocks recorded in the current session
+    fn session_clocks(&self, now: u64) -> u64 {
+        let session_clocks = self.session_clocks.load(Ordering::Relaxed);
+        if self.recording.load(Ordering::Relaxed) { session_clocks + (now - self.session_start_stamp.load(Ordering::Relaxed)) } else { session_clocks }
+    }
+
+    #[inline(always)]
+    fn timer_read(&self) -> CpuTimerRead { CpuTimerRead::ALL[self.timer_read.load(Ordering::Relaxed) as usize] }
+
+    // Should be set before any blocks are opened, stamps from different read methods are not comparable.
+    pub fn set_timer_read(&self, timer_read: CpuTimerRead) {
+        let index = CpuTimerRead::ALL.iter().position(|read| *read == timer_read).unwrap_or(0);
+        self.timer_read.store(index as u8, Ordering::Relaxed);
+        self.calibrate_overhead();
+    }
+
+    // Times batches of empty blocks on the calling thread and keeps the cheapest batch.
+    // The anchors touched are restored afterwards so calibration never shows up in the report.
+    // Calibrates the bare block: perf counters, page faults, tracing spans, the call tree, traces,
+    // block stats and live tables are all off meanwhile, so blocks pay more than calibrated with any on.
+    fn calibrate_overhead(&self) {
+        let recording = self.recording.swap(true, Ordering::Relaxed);
+        let call_tree_enabled = self.call_tree_enabled.swap(false, Ordering::Relaxed);
+        let trace_capacity = self.trace_capacity.swap(0, Ordering::Relaxed);
+        let block_stats_enabled = self.block_stats_enabled.swap(false, Ordering::Relaxed);
+        let page_faults_enabled = self.page_faults_enabled.swap(false, Ordering::Relaxed);
+        #[cfg(feature = "tracing")]
+        let tracing_spans_enabled = self.tracing_spans_enabled.swap(false, Ordering::Relaxed);
+        let live_refresh_clocks = self.live_refresh_clocks.swap(0, Ordering::Relaxed);
+        let timer_read = self.timer_read();
+        let profile_index = *CALIBRATION_PROFILE_INDEX;
+
+        let saved = with_thread_profile(|thread_profile| (thread_profile.profiles[0], *thread_profile.anchor_mut(profile_index), thread_profile.block_count, thread_profile.perf_counters.take()));
+        if let Some((saved_root, saved_anchor, saved_block_count, saved_perf_counters)) = saved {
+            let mut overhead = ProfilerOverhead { block_overhead: u64::MAX, self_overhead: u64::MAX };
+            for _ in 0..OVERHEAD_CALIBRATION_BATCH_COUNT {
+                let start_inclusive = with_thread_profile(|thread_profile| thread_profile.profiles[profile_index].elapsed_inclusive).unwrap_or(0);
+                let start_stamp = timer_read.read();
+                for _ in 0..OVERHEAD_CALIBRATION_BATCH_SIZE {
+                    drop(ProfileBlock::new(EMPTY_TAG, profile_index, 0));
+                }
+                let elapsed = timer_read.read() - start_stamp;
+                let elapsed_inclusive = with_thread_profile(|thread_profile| thread_profile.profiles[profile_index].elapsed_inclusive).unwrap_or(0) - start_inclusive;
+                overhead.block_overhead = overhead.block_overhead.min(elapsed / OVERHEAD_CALIBRATION_BATCH_SIZE);
+                overhead.self_overhead = overhead.self_overhead.min(elapsed_inclusive / OVERHEAD_CALIBRATION_BATCH_SIZE);
+            }
+            self.block_overhead.store(overhead.block_overhead, Ordering::Relaxed);
+            self.self_overhead.store(overhead.self_overhead.min(overhead.block_overhead), Ordering::Relaxed);
+
+            with_thread_profile(|thread_profile| {
+                thread_profile.profiles[0] = saved_root;
+                thread_profile.profiles[profile_index] = saved_anchor;
+                thread_profile.block_count = saved_block_count;
+                thread_profile.perf_counters = saved_perf_counters;
+            });
+        }
+
+        self.recording.store(recording, Ordering::Relaxed);
+        self.call_tree_enabled.store(call_tree_enabled, Ordering::Relaxed);
+        self.trace_capacity.store(trace_capacity, Ordering::Relaxed);
+        self.block_stats_enabled.store(block_stats_enabled, Ordering::Relaxed);
+        self.page_faults_enabled.store(page_faults_enabled, Ordering::Relaxed);
+        #[cfg(feature = "tracing")]
+        self.tracing_spans_enabled.store(tracing_spans_enabled, Ordering::Relaxed);
+        self.live_refresh_clocks.store(live_refresh_clocks, Ordering::Relaxed);
+    }
+
+    fn overhead(&self) -> ProfilerOverhead {
+        ProfilerOverhead { block_overhead: self.block_overhead.load(Ordering::Relaxed), self_overhead: self.self_overhead.load(Ordering::Relaxed) }
+    }
+
+    fn named_anchor(&self, tag: &str) -> (&'static str, usize) {
+        let mut named_anchors = self.named_anchors.lock().unwrap();
+        let named_anchors = named_anchors.get_or_insert_with(HashMap::new);
+        if let Some(named_anchor) = named_anchors.get(tag) { return *named_anchor; }
+        if named_anchors.len() >= NAMED_ANCHOR_CAPACITY { return (NAMED_OVERFLOW_TAG, *NAMED_OVERFLOW_PROFILE_INDEX); }
+        let named_anchor: (&'static str, usize) = (Box::leak(Box::from(tag)), __GLOBAL_PROFILER__COUNTER__());
+        named_anchors.insert(String::from(tag), named_anchor);
+        named_anchor
+    }
+
+    // Subtracts the calibrated cost of the profiler's own blocks from every anchor before it is printed
+    // or written to a report. Also enabled by the PROFILER_SUBTRACT_OVERHEAD env var.
+    pub fn enable_overhead_subtraction(&self) {
+        self.subtract_overhead.store(true, Ordering::Relaxed);
+    }
+
+    // Counters that fail to open are skipped, if none open the profiler continues without them.
+    // Each enabled block pays for a read() syscall on entry and exit.
+    // Applies to the calling thread and every thread that starts profiling afterwards.
+    pub fn enable_perf_counters(&self, counters: &[PerfCounter]) -> bool {
+        *self.perf_counter_selection.lock().unwrap() = counters.to_vec();
+        match PerfCounters::open(counters) {
+            Ok(perf_counters) => {
+                with_thread_profile(|thread_profile| thread_profile.perf_counters = Some(perf_counters));
+                true
+            },
+            Err(e) => {
+                eprintln!("WARNING: Hardware performance counters unavailable: {}", e);
+                false
+            }
+        }
+    }
+
+    // Records timings per call path and prints them as an indented tree after the flat report.
+    // Only blocks opened after this call are placed in the tree.
+    pub fn enable_call_tree(&self) {
+        self.call_tree_enabled.store(true, Ordering::Relaxed);
+        self.print_call_tree.store(true, Ordering::Relaxed);
+    }
+
+    // Writes the call tree to `path` as folded stacks at teardown, ready for flamegraph.pl/inferno.
+    // Records the call tree without printing it. Also enabled by the PROFILER_FOLDED env var.
+    pub fn enable_folded_stacks(&self, path: &str) {
+        *self.folded_path.lock().unwrap() = Some(String::from(path));
+        self.call_tree_enabled.store(true, Ordering::Relaxed);
+    }
+
+    // Keeps the last blocks of every thread, up to `capacity` in total, and writes them to `path` as Chrome
+    // Trace Event JSON at teardown, for chrome://tracing or Perfetto. Also enabled by the PROFILER_TRACE env var.
+    pub fn enable_trace(&self, path: &str, capacity: usize) {
+        *self.trace_path.lock().unwrap() = Some(String::from(path));
+        self.trace_capacity.store(capacity, Ordering::Relaxed);
+    }
+
+    // Records min/max/mean and a log2 histogram of every invocation's inclusive clocks, printed after
+    // the flat report. Costs a few more instructions per block. Also enabled by the PROFILER_BLOCK_STATS env var.
+    pub fn enable_block_stats(&self) {
+        self.block_stats_enabled.store(true, Ordering::Relaxed);
+    }
+
+    // Samples the thread's minor/major page faults (getrusage) and the process's resident set size on
+    // block entry and exit, adding Faults, KB/fault and RSS columns to the report. Costs four syscalls
+    // per block. Linux only, returns false elsewhere. Also enabled by the PROFILER_PAGE_FAULTS env var.
+    pub fn enable_page_faults(&self) -> bool {
+        if read_page_faults().is_none() {
+            eprintln!("WARNING: Page fault counts are unavailable on this platform.");
+            return false;
+        }
+        self.page_faults_enabled.store(true, Ordering::Relaxed);
+        true
+    }
+
+    // Makes every profile block also open a `tracing` span (see TRACING_SPAN_NAME) so tracing
+    // subscribers see the same sections as the report. Needs the profiler's `tracing` cargo feature,
+    // returns false without it. Also enabled by the PROFILER_TRACING_SPANS env var.
+    pub fn enable_tracing_spans(&self) -> bool {
+        #[cfg(feature = "tracing")]
+        {
+            self.tracing_spans_enabled.store(true, Ordering::Relaxed);
+            true
+        }
+        #[cfg(not(feature = "tracing"))]
+        {
+            eprintln!("WARNING: Tracing spans need the profiler's `tracing` feature.");
+            false
+        }
+    }
+
+    // Sends a snapshot of the merged anchor table to every client connected to `address` (see live.rs)
+    // every `interval`, e.g. for profiler-top. Threads refresh their part of the snapshot as their blocks
+    // close, so a thread sitting in one long block shows up late. Returns the address clients should
+    // connect to. Also enabled by the PROFILER_LIVE env var, with DEFAULT_LIVE_INTERVAL.
+    // Every address is served by the same publisher thread at the latest interval given, publishing
+    // on an address already being served only updates the interval. Intervals below MIN_LIVE_INTERVAL
+    // are raised to it, a zero interval would have the publisher spin on a core.
+    pub fn publish_live(&self, address: &str, interval: Duration) -> std::io::Result<String> {
+        let interval = interval.max(MIN_LIVE_INTERVAL);
+        let mut live_listeners = self.live_listeners.lock().unwrap();
+        let refresh_clocks = (interval.as_secs_f64() / 2.0 * cached_cpu_freq().median as f64) as u64;
+        self.live_refresh_clocks.store(refresh_clocks.max(1), Ordering::Relaxed);
+        self.live_interval_micros.store(interval.as_micros() as u64, Ordering::Relaxed);
+        if let Some(local_address) = live_listeners.iter().filter_map(|listener| listener.local_address().ok()).find(|local_address| local_address == address) {
+            return Ok(local_address);
+        }
+
+        let listener = LiveListener::bind(address)?;
+        let local_address = listener.local_address()?;
+        if live_listeners.is_empty() {
+            std::thread::Builder::new().name(String::from("profiler live")).spawn(|| GLOBAL_PROFILER.run_live_publisher())?;
+        }
+        live_listeners.push(listener);
+        Ok(local_address)
+    }
+
+    fn run_live_publisher(&self) {
+        let mut clients = Vec::new();
+        loop {
+            std::thread::sleep(Duration::from_micros(self.live_interval_micros.load(Ordering::Relaxed)));
+            for listener in self.live_listeners.lock().unwrap().iter() {
+                clients.extend(listener.accept_pending());
+            }
+            if clients.is_empty() { continue; }
+            let report = self.live_report();
+            // Includes clients that stopped reading, see live::LiveListener::accept_pending
+            clients.retain_mut(|client| live::write_frame(client, &report).is_ok());
+        }
+    }
+
+    // Like report(), but from the live tables of running threads rather than the calling thread's table
+    fn live_report(&self) -> ProfileReport {
+        let total_clocks = self.session_clocks(self.timer_read().read());
+        let cpu_freq = cached_cpu_freq().median;
+        let session_generation = self.session_generation.load(Ordering::Relaxed);
+        let mut merged_profiles = vec![ProfileAnchor::default(); anchor_count()];
+        let mut clocks_profiled = 0u64;
+        // Application anchors are never closed, their exclusive time is minus their children's
+        let mut merge_table = |profiles: &[ProfileAnchor]| {
+            clocks_profiled = clocks_profiled.wrapping_sub(profiles[0].elapsed_exclusive);
+            merge_anchor_table(&mut merged_profiles, profiles);
+        };
+
+        for thread in self.exited_thread_profiles.lock().unwrap().iter().filter(|thread| thread.session_generation == session_generation) {
+            merge_table(&thread.profiles);
+        }
+        for live_table in self.live_tables.lock().unwrap().iter() {
+            let live_table = live_table.lock().unwrap();
+            if live_table.session_generation == session_generation && !live_table.profiles.is_empty() {
+                merge_table(&live_table.profiles);
+            }
+        }
+
+        if self.subtract_overhead.load(Ordering::Relaxed) {
+            let overhead = self.overhead();
+            merged_profiles.iter_mut().skip(1).for_each(|profile| profile.subtract_overhead(&overhead));
+        }
+        build_report(&merged_profiles, total_clocks, clocks_profiled, cpu_freq)
+    }
+
+    pub fn print_and_deinit(&self) {
+        let teardown_start_stamp = self.teardown_start_stamp.load(Ordering::Relaxed);
+        if teardown_start_stamp > 0 && self.is_recording() {
+            let profile_index = __GLOBAL_PROFILER__COUNTER__();
+            let (perf_start, alloc_start, faults_start, start_block_count) = with_thread_profile(|thread_profile| {
+                (thread_profile.teardown_start_perf, thread_profile.teardown_start_alloc, thread_profile.teardown_start_faults, thread_profile.teardown_start_block_count)
+            }).unwrap_or_default();
+            let call_tree_parent = if self.call_tree_enabled.load(Ordering::Relaxed) {
+                with_thread_profile(|thread_profile| {
+                    let call_tree = &mut thread_profile.call_tree;
+                    let call_tree_parent = call_tree.scope;
+                    call_tree.scope = call_tree.child(call_tree_parent, profile_index);
+                    call_tree_parent
+                }).unwrap_or(NO_CALL_TREE_NODE)
+            } else { NO_CALL_TREE_NODE };
+            ProfileBlock{ tag: "app teardown", creation_stamp: teardown_start_stamp, old_elapsed_inclusive: 0, old_descendant_invocations: 0, old_faults_inclusive: PageFaults::default(), start_block_count, parent_index: 0, profile_index, perf_start, alloc_start, faults_start, call_tree_parent, sample_period: 1,
+                #[cfg(feature = "tracing")]
+                tracing_span: None
+            };
+        }
+        let end_stamp = self.timer_read().read();
+        let calibration = cached_cpu_freq();
+        let cpu_freq = calibration.median;
+
+        let runtime_clocks = end_stamp - self.creation_stamp.load(Ordering::Relaxed);
+        let total_clocks = self.session_clocks(end_stamp);
+        let total_millisecs = clocks_to_millisecs(total_clocks, cpu_freq);
+        let anchor_count = anchor_count();
+
+        let clocks_profiled = with_thread_profile(|thread_profile| {
+            self.sync_session(thread_profile);
+            // manually "drop" application-wide profile anchor
+            let profiler_profile = &mut thread_profile.profiles[0];
+            profiler_profile.elapsed_inclusive = total_clocks;
+            profiler_profile.elapsed_exclusive = profiler_profile.elapsed_exclusive.wrapping_add(total_clocks);
+            profiler_profile.elapsed_inclusive - profiler_profile.elapsed_exclusive
+        }).unwrap_or(0);
+
+        let current_thread_profile = current_thread_profile();
+        let running_thread_profiles = self.running_thread_profiles.lock().unwrap();
+        let exited_thread_profiles = self.exited_thread_profiles.lock().unwrap();
+        let running_thread_profiles = lock_thread_profiles(&running_thread_profiles, current_thread_profile);
+        let thread_profiles = self.session_thread_profiles(&running_thread_profiles, &exited_thread_profiles);
+        let mut merged_profiles = merge_thread_profiles(&thread_profiles, anchor_count);
+        // The calling thread's counters when it opened any, exited threads no longer hold theirs
+        let perf_counters = running_thread_profiles.iter().find_map(|thread_profile| thread_profile.perf_counters.as_ref());
+
+        let overhead = self.overhead();
+        let subtract_overhead = self.subtract_overhead.load(Ordering::Relaxed);
+        let block_count: u64 = merged_profiles[1..].iter().map(|profile| profile.timed_invocations()).sum();
+        if subtract_overhead {
+            merged_profiles.iter_mut().skip(1).for_each(|profile| profile.subtract_overhead(&overhead));
+        }
+
+        print!("\n====== Profiler Results *START* =======");
+        let cpu = cpu_info();
+        let nominal_freq = cpu.tsc_freq.map_or(String::from("unknown"), printable_freq);
+        print!("\nCPU: {} ({})", cpu.brand, cpu.vendor);
+        print!("\nInvariant TSC: {}, Nominal TSC freq: {}", cpu.invariant_tsc, nominal_freq);
+        if let Some(base_freq) = cpu.base_freq {
+            print!(", Base freq: {}", printable_freq(base_freq));
+        }
+        if !cpu.invariant_tsc { print!("\nWARNING: TSC is not invariant, clocks may not reflect elapsed time."); }
+        if calibration.sample_count > 0 {
+            print!("\nCPU freq: {} median, {:.2}% spread over {} samples", printable_freq(cpu_freq), calibration.spread_percent(), calibration.sample_count);
+        }
+        print!("\nTimer read: {}", self.timer_read().name());
+        if let Some(perf_counters) = perf_counters {
+            print!("\nPerf counters: {}", perf_counters.counters().map(PerfCounter::name).collect::<Vec<_>>().join(", "));
+        }
+        let thread_count: usize = thread_profiles.iter().map(|thread| thread.thread_count).sum();
+        if thread_count > 1 {
+            print!("\nThreads: {} (percentages are of total runtime and may sum past 100)", thread_count);
+        }
+        let running_thread_count = running_thread_profiles.len().saturating_sub(1);
+        if running_thread_count > 0 {
+            print!("\nWARNING: {} threads are still running, their open blocks are not included.", running_thread_count);
+        }
+        // The calibration anchor is never reported
+        let hidden_anchor_count = Lazy::get(&CALIBRATION_PROFILE_INDEX).map_or(0, |_| 1);
+        print!("\nAnchors used: {}", registered_anchor_count() - hidden_anchor_count);
+
+        let page_faults_enabled = self.page_faults_enabled.load(Ordering::Relaxed);
+        if let Some(max_rss_kb) = read_max_rss_kb().filter(|_| page_faults_enabled) {
+            print!("\nPeak RSS: {} KB", printable_large_num(max_rss_kb));
+        }
+
+        let recursion = merged_profiles.iter().any(|profile| profile.max_depth > 1);
+        let columns = AnchorColumns { perf_counters, allocations: ALLOCATIONS_COUNTED.load(Ordering::Relaxed), page_faults: page_faults_enabled, recursion };
+        print_anchor_header(cpu_freq, &columns);
+        for profile in merged_profiles[1..].iter().filter(|profile| profile.tag != EMPTY_TAG) {
+            print_anchor_row(profile, total_clocks, cpu_freq, &columns);
+        }
+
+        if thread_profiles.len() > 1 {
+            for thread in thread_profiles.iter() {
+                if thread.thread_count > 1 {
+                    print!("\n--- Thread: {} ({} threads) ---", thread.thread_name, thread.thread_count);
+                } else {
+                    print!("\n--- Thread: {} ---", thread.thread_name);
+                }
+                print_anchor_header(cpu_freq, &columns);
+                for profile in thread.profiles[1..anchor_count.min(thread.profiles.len())].iter().filter(|profile| profile.tag != EMPTY_TAG) {
+                    let mut profile = *profile;
+                    if subtract_overhead { profile.subtract_overhead(&overhead); }
+                    print_anchor_row(&profile, total_clocks, cpu_freq, &columns);
+                }
+            }
+        }
+
+        let percent_profiled = clocks_profiled as f64 / total_clocks as f64 * 100.0;
+        println!("\n{:<40}{:<25}{:<10.2}", "Total profiled:", printable_large_num(clocks_profiled), percent_profiled);
+        let overhead_clocks = block_count * overhead.block_overhead;
+        let overhead_str = format!("{} blocks @ {} clocks{}", printable_large_num(block_count), overhead.block_overhead, if subtract_overhead { ", subtracted from anchors" } else { "" });
+        println!("{:<40}{:<25}{:<10.2}{}", "Est. profiler overhead:", printable_large_num(overhead_clocks), overhead_clocks as f64 / total_clocks as f64 * 100.0, overhead_str);
+
+        if self.block_stats_enabled.load(Ordering::Relaxed) {
+            let mut merged_stats = vec![BlockStats::default(); anchor_count];
+            for thread in thread_profiles.iter() {
+                for (merged, stats) in merged_stats.iter_mut().zip(thread.block_stats.iter()) {
+                    merged.merge(stats);
+                }
+            }
+            println!("\n{:<40}{:<20}{:<20}Max clocks", "Block Stats (Invocations)", "Min clocks", "Mean clocks");
+            println!("  {:<38}{:<20}Percent\n", "Clock range", "Invocations");
+            for (profile, stats) in merged_profiles.iter().zip(merged_stats.iter()).skip(1).filter(|(_, stats)| stats.count > 0) {
+                stats.print(profile.tag);
+            }
+        }
+
+        if self.call_tree_enabled.load(Ordering::Relaxed) {
+            let mut call_tree = CallTree::default();
+            for thread in thread_profiles.iter() {
+                call_tree.merge(&thread.call_tree);
+            }
+            let children_inclusive: u64 = call_tree.nodes.iter().skip(1).filter(|node| node.parent == 0).map(|node| node.elapsed_inclusive).sum();
+            call_tree.nodes[0].elapsed_inclusive = total_clocks;
+            call_tree.nodes[0].elapsed_exclusive = total_clocks.wrapping_sub(children_inclusive);
+            call_tree.nodes[0].invocations = 1;
+            if self.print_call_tree.load(Ordering::Relaxed) {
+                call_tree.collapse_recursion().print(&merged_profiles);
+            }
+            if let Some(folded_path) = self.folded_path.lock().unwrap().as_ref() {
+                match call_tree.write_folded(folded_path, &merged_profiles) {
+                    Ok(()) => println!("\nFolded stacks: {}", folded_path),
+                    Err(e) => eprintln!("ERROR: Failed to write folded stacks {}: {}", folded_path, e)
+                }
+            }
+        }
+
+        if let Ok(report_path) = std::env::var(REPORT_PATH_ENV) {
+            let report = build_report(&merged_profiles, total_clocks, clocks_profiled, cpu_freq);
+            match report.write_to_path(&report_path) {
+                Ok(()) => println!("\nProfile report: {}", report_path),
+                Err(e) => eprintln!("ERROR: Failed to write profile report {}: {}", report_path, e)
+            }
+        }
+
+        if let Some(trace_path) = self.trace_path.lock().unwrap().as_ref() {
+            let creation_stamp = self.creation_stamp.load(Ordering::Relaxed);
+            let exited_traces = self.exited_traces.lock().unwrap();
+            let session_generation = self.session_generation.load(Ordering::Relaxed);
+            let traces: Vec<&ThreadTrace> = running_thread_profiles.iter()
+                .filter(|thread_profile| thread_profile.session_generation == session_generation)
+                .map(|thread_profile| &thread_profile.trace)
+                .chain(exited_traces.iter())
+                .collect();
+            match write_chrome_trace(trace_path, &traces, &merged_profiles, creation_stamp, runtime_clocks, cpu_freq) {
+                Ok(event_count) => {
+                    let dropped_count: u64 = traces.iter().map(|trace| trace.dropped_count).sum();
+                    println!("\nChrome trace: {} ({} events, {} dropped)", trace_path, printable_large_num(event_count as u64), printable_large_num(dropped_count));
+                },
+                Err(e) => eprintln!("ERROR: Failed to write chrome trace {}: {}", trace_path, e)
+            }
+        }
+
+        let total_clocks_str = format!("{} ({:.3}ms)", printable_large_num(total_clocks), total_millisecs);
+        if total_clocks == runtime_clocks {
+            println!("\n{:<40}{:<25}\n", "Total runtime clocks:", total_clocks_str);
+        } else {
+            let runtime_clocks_str = format!("{} ({:.3}ms)", printable_large_num(runtime_clocks), clocks_to_millisecs(runtime_clocks, cpu_freq));
+            println!("\n{:<40}{:<25}", "Total session clocks:", total_clocks_str);
+            println!("{:<40}{:<25}\n", "Total runtime clocks:", runtime_clocks_str);
+        }
+
+        println!("====== Profiler Results *END* =======\n");
+    }
+
+    // Snapshot of the session so far, from every thread that recorded in it, running or exited.
+    // Blocks that are still open are not included. Total profiled covers the calling thread only.
+    pub fn report(&self) -> ProfileReport {
+        let total_clocks = self.session_clocks(self.timer_read().read());
+        let cpu_freq = cached_cpu_freq().median;
+        let anchor_count = anchor_count();
+        let clocks_profiled = with_thread_profile(|thread_profile| {
+            self.sync_session(thread_profile);
+            // The application anchor has not been "dropped" yet, its exclusive time is minus its children's
+            let in_session = thread_profile.session_generation == self.session_generation.load(Ordering::Relaxed);
+            if in_session { 0u64.wrapping_sub(thread_profile.profiles[0].elapsed_exclusive) } else { 0 }
+        }).unwrap_or(0);
+
+        let current_thread_profile = current_thread_profile();
+        let running_thread_profiles = self.running_thread_profiles.lock().unwrap();
+        let exited_thread_profiles = self.exited_thread_profiles.lock().unwrap();
+        let running_thread_profiles = lock_thread_profiles(&running_thread_profiles, current_thread_profile);
+        let thread_profiles = self.session_thread_profiles(&running_thread_profiles, &exited_thread_profiles);
+        let mut merged_profiles = merge_thread_profiles(&thread_profiles, anchor_count);
+        if self.subtract_overhead.load(Ordering::Relaxed) {
+            let overhead = self.overhead();
+            merged_profiles.iter_mut().skip(1).for_each(|profile| profile.subtract_overhead(&overhead));
+        }
+        build_report(&merged_profiles, total_clocks, clocks_profiled, cpu_freq)
+    }
 
-#[cfg(feature = "profile")]
-use utils::{ printable_freq, printable_large_num};
+    // Like report(), split per thread as in the printed breakdown: running threads, the calling one first,
+    // then exited threads folded together by name
+    pub fn thread_reports(&self) -> Vec<ThreadReport> {
+        let total_clocks = self.session_clocks(self.timer_read().read());
+        let cpu_freq = cached_cpu_freq().median;
+        let anchor_count = anchor_count();
+        let overhead = self.subtract_overhead.load(Ordering::Relaxed).then(|| self.overhead());
+        with_thread_profile(|thread_profile| self.sync_session(thread_profile));
 
-#[cfg(feature = "profile")]
-const PROFILE_CAPACITY: usize = 4096;
-#[cfg(feature = "profile")]
-static EMPTY_TAG: &str = "";
+        let current_thread_profile = current_thread_profile();
+        let running_thread_profiles = self.running_thread_profiles.lock().unwrap();
+        let exited_thread_profiles = self.exited_thread_profiles.lock().unwrap();
+        let running_thread_profiles = lock_thread_profiles(&running_thread_profiles, current_thread_profile);
+        self.session_thread_profiles(&running_thread_profiles, &exited_thread_profiles).iter().map(|thread| {
+            let clocks_profiled = 0u64.wrapping_sub(thread.profiles[0].elapsed_exclusive);
+            let mut profiles = merge_thread_profiles(&[thread], anchor_count);
+            if let Some(overhead) = &overhead {
+                profiles.iter_mut().skip(1).for_each(|profile| profile.subtract_overhead(overhead));
+            }
+            let report = build_report(&profiles, total_clocks, clocks_profiled, cpu_freq);
+            ThreadReport { thread_name: thread.thread_name.clone(), thread_count: thread.thread_count, report }
+        }).collect()
+    }
 
-// NOTE: This is only used for the profiler macros. DO NOT use this for anything else. 
-#[cfg(feature = "profile")]
-#[allow(non_snake_case)]
-pub fn __GLOBAL_PROFILER__COUNTER__() -> usize {
-    static mut COUNTER: usize = 0;
-    unsafe {
-        // Counting starting at 1 is intended and not to be changed
-        // 0 is reserved for a default value
-        COUNTER += 1;
-        COUNTER
+    pub fn time_teardown(&self) {
+        with_thread_profile(|thread_profile| {
+            thread_profile.teardown_start_perf = thread_profile.read_perf_counters().unwrap_or_default();
+            thread_profile.teardown_start_alloc = read_alloc_counts();
+            thread_profile.teardown_start_faults = if self.page_faults_enabled.load(Ordering::Relaxed) { read_page_faults() } else { None };
+            thread_profile.teardown_start_block_count = thread_profile.block_count;
+        });
+        self.teardown_start_stamp.store(self.timer_read().read(), Ordering::Relaxed);
     }
 }
 
 #[cfg(feature = "profile")]
-#[derive(Clone, Copy, Default)]
-pub struct ProfileAnchor {
-    tag: &'static str,
-    elapsed_exclusive: u64, // Does not include children
-    elapsed_inclusive: u64, // Does include children
-    invocations: u64,
-    processed_byte_count: u64,
+#[derive(Clone, Debug)]
+pub struct ThreadReport {
+    pub thread_name: String,
+    pub thread_count: usize, // Exited threads sharing a name are reported together
+    pub report: ProfileReport
 }
 
+// Sums every thread's table per anchor index, the application anchor (index 0) is left empty
 #[cfg(feature = "profile")]
-pub struct Profiler {
-    profiles: [ProfileAnchor; PROFILE_CAPACITY],
-    creation_stamp: u64,
-    teardown_start_stamp: u64
+fn merge_thread_profiles(thread_profiles: &[&ThreadProfile], anchor_count: usize) -> Vec<ProfileAnchor> {
+    let mut merged_profiles = vec![ProfileAnchor::default(); anchor_count];
+    for thread_profile in thread_profiles.iter() {
+        merge_anchor_table(&mut merged_profiles, &thread_profile.profiles);
+    }
+    merged_profiles
 }
-#[cfg(feature = "profile")]
-pub static mut GLOBAL_PROFILER: Profiler = Profiler{ 
-    profiles: [ProfileAnchor{ tag: EMPTY_TAG, elapsed_exclusive: 0, elapsed_inclusive: 0, invocations: 0, processed_byte_count: 0 }; PROFILE_CAPACITY],
-    creation_stamp: 0,
-    teardown_start_stamp: 0
-};
-#[cfg(feature = "profile")]
-pub static mut GLOBAL_PROFILER_SCOPE: usize = 0;
 
 #[cfg(feature = "profile")]
-pub struct ProfileBlock {
-    tag: &'static str,
-    creation_stamp: u64,
-    old_elapsed_inclusive: u64,
-    parent_index: usize,
-    profile_index: usize
+fn merge_anchor_table(merged_profiles: &mut [ProfileAnchor], profiles: &[ProfileAnchor]) {
+    for (merged, profile) in merged_profiles.iter_mut().zip(profiles.iter()).skip(1) {
+        merged.merge(profile);
+    }
 }
 
 #[cfg(feature = "profile")]
-impl ProfileBlock {
-    pub fn new(tag: &'static str, profile_index: usize, byte_count: u64) -> Self {
-        let parent_index;
-        let old_elapsed_inclusive;
-        unsafe {
-            parent_index = GLOBAL_PROFILER_SCOPE;
-            GLOBAL_PROFILER_SCOPE = profile_index;
-            let profile_anchor = &mut GLOBAL_PROFILER.profiles[profile_index];
-            old_elapsed_inclusive = profile_anchor.elapsed_inclusive;
-            profile_anchor.processed_byte_count += byte_count;
-        };
-        ProfileBlock {
-            tag,
-            creation_stamp: read_cpu_timer(),
-            old_elapsed_inclusive,
-            parent_index,
-            profile_index
-        }
-    }
+fn build_report(merged_profiles: &[ProfileAnchor], total_clocks: u64, clocks_profiled: u64, cpu_freq: u64) -> ProfileReport {
+    let entries = merged_profiles[1..].iter()
+        .filter(|profile| profile.tag != EMPTY_TAG)
+        .map(|profile| ProfileReportEntry {
+            sample_period: profile.sample_period,
+            ..ProfileReportEntry::new(profile.tag, profile.invocations, profile.exclusive_clocks(), profile.elapsed_inclusive, profile.processed_byte_count, cpu_freq)
+        })
+        .collect();
+    ProfileReport { cpu_freq, total_clocks, clocks_profiled, entries }
 }
 
+// Writes complete ("X") events with microsecond timestamps relative to profiler init
 #[cfg(feature = "profile")]
-impl Drop for ProfileBlock {
-    fn drop(&mut self) {
-        let elapsed: u64 = read_cpu_timer() - self.creation_stamp;
-        unsafe {
-            GLOBAL_PROFILER_SCOPE = self.parent_index;
+fn write_chrome_trace(path: &str, traces: &[&ThreadTrace], profiles: &[ProfileAnchor], creation_stamp: u64, total_clocks: u64, cpu_freq: u64) -> std::io::Result<usize> {
+    use std::io::Write;
 
-            let parent_profile = &mut GLOBAL_PROFILER.profiles[self.parent_index];
-            let profile = &mut GLOBAL_PROFILER.profiles[self.profile_index];
+    let mut writer = std::io::BufWriter::new(std::fs::File::create(path)?);
+    let clocks_to_micros = |clocks: u64| clocks as f64 * 1_000_000.0 / cpu_freq as f64;
+    let mut event_count = 0;
 
-            parent_profile.elapsed_exclusive = parent_profile.elapsed_exclusive.wrapping_sub(elapsed);
-            profile.elapsed_exclusive = profile.elapsed_exclusive.wrapping_add(elapsed);
-            profile.elapsed_inclusive = self.old_elapsed_inclusive + elapsed;
-            profile.invocations += 1;
-            profile.tag = self.tag;
+    write!(writer, "{{\"traceEvents\":[")?;
+    write!(writer, "\n{{\"name\":\"Application\",\"ph\":\"X\",\"ts\":0,\"dur\":{:.3},\"pid\":1,\"tid\":0}}", clocks_to_micros(total_clocks))?;
+    for (thread_id, trace) in traces.iter().enumerate() {
+        write!(writer, ",\n{{\"name\":\"thread_name\",\"ph\":\"M\",\"pid\":1,\"tid\":{},\"args\":{{\"name\":\"{}\"}}}}", thread_id, json_escape(&trace.thread_name))?;
+        for event in trace.events.iter() {
+            let tag = profiles.get(event.profile_index).map_or(EMPTY_TAG, |profile| profile.tag);
+            write!(writer, ",\n{{\"name\":\"{}\",\"ph\":\"X\",\"ts\":{:.3},\"dur\":{:.3},\"pid\":1,\"tid\":{}}}",
+                json_escape(tag), clocks_to_micros(event.start_stamp.wrapping_sub(creation_stamp)), clocks_to_micros(event.elapsed), thread_id)?;
+            event_count += 1;
         }
     }
+    writeln!(writer, "\n],\"displayTimeUnit\":\"ns\"}}")?;
+    writer.flush()?;
+    Ok(event_count)
 }
 
+// Optional columns of the anchor table
 #[cfg(feature = "profile")]
-impl Profiler {
-    pub fn init(&mut self) {
-        unsafe { 
-            GLOBAL_PROFILER.creation_stamp = read_cpu_timer(); 
-            GLOBAL_PROFILER.profiles[0].tag = "Application";
-        }
-    }
-
-    pub fn print_and_deinit(&mut self) {
-        if self.teardown_start_stamp > 0 {
-            ProfileBlock{ tag: "app teardown", creation_stamp: self.teardown_start_stamp, old_elapsed_inclusive: 0, parent_index: 0, profile_index: __GLOBAL_PROFILER__COUNTER__() };
-        }
-        let end_stamp = read_cpu_timer();
-        let cpu_freq = measure_cpu_freq(100);
-
-        let total_clocks = end_stamp - self.creation_stamp;
-        let total_millisecs = clocks_to_millisecs(total_clocks, cpu_freq);
-
-        // manually "drop" application-wide profile anchor
-        let profiler_profile = &mut self.profiles[0];
-        profiler_profile.elapsed_inclusive = total_clocks;
-        profiler_profile.elapsed_exclusive = profiler_profile.elapsed_exclusive.wrapping_add(total_clocks);
-        let clocks_profiled = profiler_profile.elapsed_inclusive - profiler_profile.elapsed_exclusive;
-
-        print!("\n====== Profiler Results *START* =======");
-
-        let clocks_column_title = format!("Clocks @ {}", printable_freq(cpu_freq));
-        println!("\n{:<40}{:<25}{:<10}{:<15}{}\n", "Tag (Invocations)", clocks_column_title, "Percent", "w/ Children", "Bandwidth");
-
-        for profile in self.profiles[1..].iter() {
-            if profile.tag != EMPTY_TAG {
-                    let percent_exclusive = profile.elapsed_exclusive as f64 / total_clocks as f64 * 100.0;
-                    let percent_inclusive = profile.elapsed_inclusive as f64 / total_clocks as f64 * 100.0;
-                    let title_str = format!("{} ({}):", profile.tag, printable_large_num(profile.invocations));
-                    let bandwidth = if profile.processed_byte_count <= 0 { String::from("") } else { 
-                        let megabyte = 1024.0*1024.0;
-                        let gigabyte = 1024.0*megabyte;
-                        let seconds = profile.elapsed_inclusive as f64 / cpu_freq as f64;
-                        let bytes_per_second = profile.processed_byte_count as f64 / seconds;
-                        let megabytes = profile.processed_byte_count as f64 / megabyte;
-                        let gigabytes_per_second = bytes_per_second / gigabyte;
-                        format!("{:.3}mb @ {:.2}gb/s", megabytes, gigabytes_per_second)
-                    };
-                    println!("{:<40}{:<25}{:<10.2}{:<15.2}{}", title_str, printable_large_num(profile.elapsed_exclusive), percent_exclusive, percent_inclusive, bandwidth);
-            } else {
-                break;
-            }
-        }
-        
-        let percent_profiled = clocks_profiled as f64 / total_clocks as f64 * 100.0;
-        println!("\n{:<40}{:<25}{:<10.2}", "Total profiled:", printable_large_num(clocks_profiled as u64), percent_profiled);
-        
-        let total_clocks_str = format!("{} ({:.3}ms)", printable_large_num(total_clocks), total_millisecs);
-        println!("\n{:<40}{:<25}\n", "Total runtime clocks:", total_clocks_str);
+struct AnchorColumns<'a> {
+    perf_counters: Option<&'a PerfCounters>,
+    allocations: bool,
+    page_faults: bool,
+    recursion: bool // Only shown when some anchor recursed
+}
 
-        println!("====== Profiler Results *END* =======\n");
+#[cfg(feature = "profile")]
+fn print_anchor_header(cpu_freq: u64, columns: &AnchorColumns) {
+    let clocks_column_title = format!("Clocks @ {}", printable_freq(cpu_freq));
+    let mut header = format!("{:<40}{:<25}{:<10}{:<15}{:<30}", "Tag (Invocations)", clocks_column_title, "Percent", "w/ Children", "Bandwidth");
+    if columns.recursion {
+        header.push_str(&format!("{:<12}", "Max depth"));
     }
+    if columns.page_faults {
+        header.push_str(&format!("{:<20}{:<12}{:<14}", "Faults (major)", "KB/fault", "RSS delta"));
+    }
+    if columns.allocations {
+        header.push_str(&format!("{:<12}{:<16}{:<16}", "Allocs", "Allocated", "Freed"));
+    }
+    if columns.perf_counters.is_some() {
+        header.push_str(&format!("{:<8}{:<12}{}", "IPC", "Cache miss", "Branch miss"));
+    }
+    println!("\n{}\n", header.trim_end());
+}
 
-    pub fn time_teardown(&mut self) { self.teardown_start_stamp = read_cpu_timer(); }
+#[cfg(feature = "profile")]
+fn print_anchor_row(profile: &ProfileAnchor, total_clocks: u64, cpu_freq: u64, columns: &AnchorColumns) {
+    let percent_exclusive = profile.exclusive_clocks() as f64 / total_clocks as f64 * 100.0;
+    let percent_inclusive = profile.elapsed_inclusive as f64 / total_clocks as f64 * 100.0;
+    let title_str = if profile.sample_period > 1 {
+        format!("{} ({}, sampled 1/{}):", profile.tag, printable_large_num(profile.invocations), profile.sample_period)
+    } else {
+        format!("{} ({}):", profile.tag, printable_large_num(profile.invocations))
+    };
+    let bandwidth = if profile.processed_byte_count == 0 { String::from("") } else { 
+        let megabyte = 1024.0*1024.0;
+        let gigabyte = 1024.0*megabyte;
+        let seconds = profile.elapsed_inclusive as f64 / cpu_freq as f64;
+        let bytes_per_second = profile.processed_byte_count as f64 / seconds;
+        let megabytes = profile.processed_byte_count as f64 / megabyte;
+        let gigabytes_per_second = bytes_per_second / gigabyte;
+        format!("{:.3}mb @ {:.2}gb/s", megabytes, gigabytes_per_second)
+    };
+    let mut row = format!("{:<40}{:<25}{:<10.2}{:<15.2}{:<30}", title_str, printable_large_num(profile.exclusive_clocks()), percent_exclusive, percent_inclusive, bandwidth);
+    if columns.recursion {
+        row.push_str(&format!("{:<12}", profile.max_depth));
+    }
+    if columns.page_faults {
+        // Like repetition_testing: bytes processed per fault, both including children like the bandwidth
+        let faults = &profile.faults_exclusive;
+        let faults_inclusive = profile.faults_inclusive.total();
+        let kb_per_fault = if faults_inclusive == 0 || profile.processed_byte_count == 0 { String::from("-") } else {
+            format!("{:.4}", profile.processed_byte_count as f64 / 1024.0 / faults_inclusive as f64)
+        };
+        let resident_kb_delta = faults.resident_kb_delta();
+        let rss_delta = format!("{}{} KB", if resident_kb_delta < 0 { "-" } else { "+" }, printable_large_num(resident_kb_delta.unsigned_abs()));
+        row.push_str(&format!("{:<20}{:<12}{:<14}", format!("{} ({})", printable_large_num(faults.total()), printable_large_num(faults.major)), kb_per_fault, rss_delta));
+    }
+    if columns.allocations {
+        let alloc = &profile.alloc_exclusive;
+        row.push_str(&format!("{:<12}{:<16}{:<16}", printable_large_num(alloc.count), printable_large_num(alloc.allocated_bytes), printable_large_num(alloc.freed_bytes)));
+    }
+    if let Some(perf_counters) = columns.perf_counters {
+        // Ratios are only printed when both of their counters were opened
+        let ratio = |numerator: PerfCounter, denominator: PerfCounter, scale: f64, precision: usize, suffix: &str| -> String {
+            let denominator_count = profile.perf_exclusive[denominator as usize];
+            if !perf_counters.has(numerator) || !perf_counters.has(denominator) || denominator_count == 0 { return String::from("-"); }
+            let value = profile.perf_exclusive[numerator as usize] as f64 / denominator_count as f64 * scale;
+            format!("{:.*}{}", precision, value, suffix)
+        };
+        let ipc = ratio(PerfCounter::Instructions, PerfCounter::Cycles, 1.0, 2, "");
+        let cache_miss = ratio(PerfCounter::CacheMisses, PerfCounter::CacheReferences, 100.0, 2, "%");
+        let branch_miss = ratio(PerfCounter::BranchMisses, PerfCounter::BranchInstructions, 100.0, 2, "%");
+        row.push_str(&format!("{:<8}{:<12}{}", ipc, cache_miss, branch_miss));
+    }
+    println!("{}", row.trim_end());
 }
 
 #[cfg(feature = "profile")]
@@ -194,6 +1729,46 @@ macro_rules! time_block {
     }
 }
 
+// Tag is any runtime &str/String, or format arguments: time_named_block!("parse file {}", name)
+// Format arguments are only formatted while recording. See ProfileBlock::named for how many tags are kept apart.
+#[cfg(feature = "profile")]
+#[macro_export]
+macro_rules! time_named_block {
+    ( $fmt:literal, $($arg:tt)+ ) => {
+        let __profile_block = profiler::ProfileBlock::named_args(format_args!($fmt, $($arg)+), 0);
+    };
+    ( $tag:expr ) => {
+        let __profile_block = profiler::ProfileBlock::named(&$tag, 0);
+    }
+}
+
+#[cfg(feature = "profile")]
+#[macro_export]
+macro_rules! time_named_bandwidth_block {
+    ( $tag:expr, $byte_count:expr ) => {
+        let __profile_block = profiler::ProfileBlock::named(&$tag, $byte_count);
+    }
+}
+
+// Name of the enclosing function, used as the tag of the *_function macros
+#[doc(hidden)]
+#[cfg(feature = "profile")]
+#[macro_export]
+macro_rules! __profiler_function_tag {
+    () => {{
+        fn f() {}
+        #[inline(always)]
+        fn type_name_of<T>(_: T) -> &'static str { std::any::type_name::<T>() }
+        static PROFILE_TAG: Lazy<&'static str> = Lazy::new(|| { 
+                let name = type_name_of(f);
+                let func_name_start = &name[..name.len() - 3].rfind(':').expect("Failed to find function name.") + 1;
+                let func_name_end = name.len() - 3; // remove the trailing "::f"
+                &name[func_name_start..func_name_end]
+        });
+        *PROFILE_TAG
+    }};
+}
+
 /*
 Calling this macro twice in the same function will *NOT* compile.
 */
@@ -202,19 +1777,7 @@ Calling this macro twice in the same function will *NOT* compile.
 macro_rules! time_bandwidth_function {
     // `()` indicates that the macro takes no argument.
     ($byte_count: expr) => {
-        let __profiler_tag: &'static str;
-        unsafe {
-            fn f() {}
-            #[inline(always)]
-            fn type_name_of<T>(_: T) -> &'static str { std::any::type_name::<T>() }
-            static PROFILE_TAG: Lazy<&'static str> = Lazy::new(|| { 
-                    let name = type_name_of(f);
-                    let func_name_start = &name[..name.len() - 3].rfind(':').expect("Failed to find function name.") + 1;
-                    let func_name_end = name.len() - 3; // remove the trailing "::f"
-                    &name[func_name_start..func_name_end]
-            });
-            __profiler_tag = *PROFILE_TAG;
-        };
+        let __profiler_tag: &'static str = profiler::__profiler_function_tag!();
         profiler::time_bandwidth_block!(__profiler_tag, $byte_count);
     }
 }
@@ -225,14 +1788,37 @@ macro_rules! time_function {
     () => { profiler::time_bandwidth_function!(0); }
 }
 
+// Times 1 in $sample_period invocations and extrapolates, see ProfileBlock::sampled
+#[cfg(feature = "profile")]
+#[macro_export]
+macro_rules! time_sampled_block {
+    ( $tag:expr, $sample_period:expr ) => {
+        let __profiler_index: usize;
+        unsafe {
+            static PROFILE_INDEX: Lazy<usize> = Lazy::new(|| { profiler::__GLOBAL_PROFILER__COUNTER__() });
+            __profiler_index = *PROFILE_INDEX;
+        };
+        let __profile_block = profiler::ProfileBlock::sampled($tag, __profiler_index, 0, $sample_period);
+    }
+}
+
+#[cfg(feature = "profile")]
+#[macro_export]
+macro_rules! time_sampled_function {
+    ( $sample_period:expr ) => {
+        let __profiler_tag: &'static str = profiler::__profiler_function_tag!();
+        profiler::time_sampled_block!(__profiler_tag, $sample_period);
+    }
+}
+
 #[cfg(feature = "profile")]
 #[macro_export]
 macro_rules! profiler_setup_defer_teardown {
     () => {
         // This is kept even if not profiling, as it does not add any overhead during the running of the application
-        unsafe { profiler::GLOBAL_PROFILER.init(); } 
+        $crate::GLOBAL_PROFILER.init();
         
-        let _defer_unregister = Defer::new(|| { unsafe { profiler::GLOBAL_PROFILER.print_and_deinit(); } });
+        let _defer_unregister = $crate::Defer::new(|| { $crate::GLOBAL_PROFILER.print_and_deinit(); });
     }
 }
 
@@ -261,8 +1847,8 @@ macro_rules! time_assignment_rhs  {
             static PROFILE_TAG: Lazy<&'static str> = Lazy::new(|| {
                 let stmt = stringify!($x);
                 if let Some(equal_index) = stmt.find('=') {
-                    stmt[equal_index + 1..stmt.len()-1].trim_start()
-                } else { &stmt[..stmt.len()-1] };
+                    stmt[equal_index + 1..].trim()
+                } else { stmt }
             });
             static PROFILE_INDEX: Lazy<usize> = Lazy::new(|| { profiler::__GLOBAL_PROFILER__COUNTER__() });
             __profiler_tag = *PROFILE_TAG;
@@ -307,39 +1893,131 @@ macro_rules! time_assignments {
 
 #[cfg(feature = "profile")]
 #[macro_export]
-macro_rules! time_teardown { () => { unsafe{ profiler::GLOBAL_PROFILER.time_teardown(); } } }
+macro_rules! time_teardown { () => { profiler::GLOBAL_PROFILER.time_teardown(); } }
+
+#[cfg(feature = "profile")]
+#[macro_export]
+macro_rules! profiler_start { () => { profiler::GLOBAL_PROFILER.start(); } }
+
+#[cfg(feature = "profile")]
+#[macro_export]
+macro_rules! profiler_stop { () => { profiler::GLOBAL_PROFILER.stop(); } }
+
+#[cfg(feature = "profile")]
+#[macro_export]
+macro_rules! profiler_reset { () => { profiler::GLOBAL_PROFILER.reset(); } }
+
+#[cfg(feature = "profile")]
+#[macro_export]
+macro_rules! profiler_set_timer_read { ( $timer_read:expr ) => { profiler::GLOBAL_PROFILER.set_timer_read($timer_read); } }
+
+#[cfg(feature = "profile")]
+#[macro_export]
+macro_rules! profiler_enable_perf_counters { ( $counters:expr ) => { profiler::GLOBAL_PROFILER.enable_perf_counters($counters); } }
+
+#[cfg(feature = "profile")]
+#[macro_export]
+macro_rules! profiler_enable_call_tree { () => { profiler::GLOBAL_PROFILER.enable_call_tree(); } }
+
+#[cfg(feature = "profile")]
+#[macro_export]
+macro_rules! profiler_enable_folded_stacks { ( $path:expr ) => { profiler::GLOBAL_PROFILER.enable_folded_stacks($path); } }
+
+#[cfg(feature = "profile")]
+#[macro_export]
+macro_rules! profiler_enable_trace { ( $path:expr, $capacity:expr ) => { profiler::GLOBAL_PROFILER.enable_trace($path, $capacity); } }
+
+#[cfg(feature = "profile")]
+#[macro_export]
+macro_rules! profiler_enable_block_stats { () => { profiler::GLOBAL_PROFILER.enable_block_stats(); } }
+
+#[cfg(feature = "profile")]
+#[macro_export]
+macro_rules! profiler_enable_page_faults { () => { profiler::GLOBAL_PROFILER.enable_page_faults(); } }
+
+#[cfg(feature = "profile")]
+#[macro_export]
+macro_rules! profiler_enable_overhead_subtraction { () => { profiler::GLOBAL_PROFILER.enable_overhead_subtraction(); } }
+
+#[cfg(feature = "profile")]
+#[macro_export]
+macro_rules! profiler_enable_tracing_spans { () => { profiler::GLOBAL_PROFILER.enable_tracing_spans(); } }
+
+#[cfg(feature = "profile")]
+#[macro_export]
+macro_rules! profiler_publish_live {
+    ( $address:expr ) => { profiler::profiler_publish_live!($address, profiler::live::DEFAULT_LIVE_INTERVAL) };
+    ( $address:expr, $interval:expr ) => {
+        if let Err(error) = profiler::GLOBAL_PROFILER.publish_live($address, $interval) {
+            eprintln!("WARNING: Failed to publish live profiler results on {}: {}", $address, error);
+        }
+    };
+}
 
 /* 
     Alternative macros for when profiling is *NOT* enabled.
+    Every macro above has a mirror here taking the same arguments. Arguments that only feed the profiler
+    (tags, byte counts, paths, capacities) are type checked behind `if false` but *NOT* evaluated, so they must
+    not be relied on for side effects. Statements passed to time_section!/time_assignment*! are still run.
  */
 #[cfg(not(feature = "profile"))]
 pub use utils::{ printable_freq, printable_large_num};
 #[cfg(not(feature = "profile"))]
-pub use clocks::{measure_cpu_freq, clocks_to_millisecs, read_cpu_timer};
+pub use clocks::{cached_cpu_freq, clocks_to_millisecs, read_cpu_timer, CpuTimerRead};
+#[cfg(not(feature = "profile"))]
+pub use clocks::perf::PerfCounter;
+
+#[cfg(not(feature = "profile"))]
+#[macro_export]
+macro_rules! time_bandwidth_block { ( $tag:expr, $byte_count:expr ) => { if false { let _ = (&$tag, &$byte_count); } } }
+
+#[cfg(not(feature = "profile"))]
+#[macro_export]
+macro_rules! time_block { ( $msg:expr ) => { if false { let _ = &$msg; } } }
 
 #[cfg(not(feature = "profile"))]
 #[macro_export]
-macro_rules! time_block { ( $msg:expr ) => {} }
+macro_rules! time_bandwidth_function { ( $byte_count:expr ) => { if false { let _ = &$byte_count; } } }
 
 #[cfg(not(feature = "profile"))]
 #[macro_export]
 macro_rules! time_function { () => {} }
 
+#[cfg(not(feature = "profile"))]
+#[macro_export]
+macro_rules! time_sampled_block { ( $tag:expr, $sample_period:expr ) => { if false { let _ = (&$tag, &$sample_period); } } }
+
+#[cfg(not(feature = "profile"))]
+#[macro_export]
+macro_rules! time_sampled_function { ( $sample_period:expr ) => { if false { let _ = &$sample_period; } } }
+
+// The tag is never formatted, so it costs nothing when not profiling
+#[cfg(not(feature = "profile"))]
+#[macro_export]
+macro_rules! time_named_block {
+    ( $fmt:literal, $($arg:tt)+ ) => { if false { let _ = format!($fmt, $($arg)+); } };
+    ( $tag:expr ) => { if false { let _ = &$tag; } }
+}
+
+#[cfg(not(feature = "profile"))]
+#[macro_export]
+macro_rules! time_named_bandwidth_block { ( $tag:expr, $byte_count:expr ) => { if false { let _ = (&$tag, &$byte_count); } } }
+
 #[cfg(not(feature = "profile"))]
 #[macro_export]
 macro_rules! profiler_setup_defer_teardown {
     () => {
         // Note: I still want to get some information on the total runtime of the program.
-        let start_stamp = read_cpu_timer();
+        let start_stamp = $crate::read_cpu_timer();
         
-        let _defer_unregister = Defer::new(|| { 
-            let end_stamp = read_cpu_timer();
-            let cpu_freq = measure_cpu_freq(100);
+        let _defer_unregister = $crate::Defer::new(|| { 
+            let end_stamp = $crate::read_cpu_timer();
+            let cpu_freq = $crate::cached_cpu_freq().median;
             let total_clocks = end_stamp - start_stamp;
-            let total_millisecs = clocks_to_millisecs(total_clocks, cpu_freq);
-            let total_clocks_str = format!("{} ({:.3}ms)", printable_large_num(total_clocks), total_millisecs);
+            let total_millisecs = $crate::clocks_to_millisecs(total_clocks, cpu_freq);
+            let total_clocks_str = format!("{} ({:.3}ms)", $crate::printable_large_num(total_clocks), total_millisecs);
             println!("\nProfiler turned off.");
-            println!("Clocks @ {}", printable_freq(cpu_freq));
+            println!("Clocks @ {}", $crate::printable_freq(cpu_freq));
             println!("Total runtime clocks: {}\n", total_clocks_str);
          });
     }
@@ -366,9 +2044,272 @@ macro_rules! time_assignment  {
 #[cfg(not(feature = "profile"))]
 #[macro_export]
 macro_rules! time_assignments {
-    ($($x:stmt);* $(;)?) => { $($x)* };
+    ($($x:stmt);* $(;)?) => { $($x;)* };
 }
 
 #[cfg(not(feature = "profile"))]
 #[macro_export]
 macro_rules! time_teardown { () => {} }
+
+#[cfg(not(feature = "profile"))]
+#[macro_export]
+macro_rules! profiler_start { () => {} }
+
+#[cfg(not(feature = "profile"))]
+#[macro_export]
+macro_rules! profiler_stop { () => {} }
+
+#[cfg(not(feature = "profile"))]
+#[macro_export]
+macro_rules! profiler_reset { () => {} }
+
+#[cfg(not(feature = "profile"))]
+#[macro_export]
+macro_rules! profiler_set_timer_read { ( $timer_read:expr ) => { if false { let _ = &$timer_read; } } }
+
+#[cfg(not(feature = "profile"))]
+#[macro_export]
+macro_rules! profiler_enable_perf_counters { ( $counters:expr ) => { if false { let _ = &$counters; } } }
+
+#[cfg(not(feature = "profile"))]
+#[macro_export]
+macro_rules! profiler_enable_call_tree { () => {} }
+
+#[cfg(not(feature = "profile"))]
+#[macro_export]
+macro_rules! profiler_enable_trace { ( $path:expr, $capacity:expr ) => { if false { let _ = (&$path, &$capacity); } } }
+
+#[cfg(not(feature = "profile"))]
+#[macro_export]
+macro_rules! profiler_enable_folded_stacks { ( $path:expr ) => { if false { let _ = &$path; } } }
+
+#[cfg(not(feature = "profile"))]
+#[macro_export]
+macro_rules! profiler_enable_block_stats { () => {} }
+
+#[cfg(not(feature = "profile"))]
+#[macro_export]
+macro_rules! profiler_enable_page_faults { () => {} }
+
+#[cfg(not(feature = "profile"))]
+#[macro_export]
+macro_rules! profiler_enable_overhead_subtraction { () => {} }
+
+#[cfg(not(feature = "profile"))]
+#[macro_export]
+macro_rules! profiler_enable_tracing_spans { () => {} }
+
+#[cfg(not(feature = "profile"))]
+#[macro_export]
+macro_rules! profiler_publish_live {
+    ( $address:expr ) => { if false { let _ = &$address; } };
+    ( $address:expr, $interval:expr ) => { if false { let _ = (&$address, &$interval); } };
+}
+
+// Blocks opened here use fixed anchor indices, each test thread gets its own table
+#[cfg(all(test, feature = "profile"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn block_stats_buckets_by_log2() {
+        let mut stats = BlockStats::default();
+        for elapsed in [0, 1, 2, 3, 4, 1000] {
+            stats.record(elapsed);
+        }
+        assert_eq!(stats.histogram[0], 1); // 0
+        assert_eq!(stats.histogram[1], 1); // 1
+        assert_eq!(stats.histogram[2], 2); // 2-3
+        assert_eq!(stats.histogram[3], 1); // 4-7
+        assert_eq!(stats.histogram[10], 1); // 512-1023
+        assert_eq!(BlockStats::bucket(u64::MAX), BLOCK_STATS_BUCKET_COUNT - 1);
+        assert_eq!((stats.min, stats.max, stats.count), (0, 1000, 6));
+    }
+
+    #[test]
+    fn block_stats_merge() {
+        let mut a = BlockStats::default();
+        let mut b = BlockStats::default();
+        a.record(10);
+        b.record(30);
+        b.record(50);
+        a.merge(&b);
+        assert_eq!((a.min, a.max, a.count), (10, 50, 3));
+        assert_eq!(a.mean(), 30.0);
+        assert_eq!(a.histogram.iter().sum::<u64>(), 3);
+    }
+
+    #[test]
+    fn named_blocks_intern_tags() {
+        let (tag, index) = GLOBAL_PROFILER.named_anchor(&format!("parse file {}", "a.json"));
+        assert_eq!(tag, "parse file a.json");
+        assert_eq!(GLOBAL_PROFILER.named_anchor("parse file a.json"), (tag, index));
+        assert_eq!(GLOBAL_PROFILER.named_anchor("parse file b.json").0, "parse file b.json");
+        assert_ne!(GLOBAL_PROFILER.named_anchor("parse file b.json").1, index);
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    #[test]
+    fn allocations_attributed_to_innermost_block() {
+        let outer = ProfileBlock::new("alloc outer", 1, 0);
+        let outer_bytes = std::hint::black_box(Vec::<u8>::with_capacity(100));
+        let inner = ProfileBlock::new("alloc inner", 2, 0);
+        drop(std::hint::black_box(Vec::<u8>::with_capacity(1000)));
+        drop(inner);
+        drop(outer_bytes);
+        drop(outer);
+
+        let (outer_alloc, inner_alloc) = with_thread_profile(|thread_profile| (thread_profile.profiles[1].alloc_exclusive, thread_profile.profiles[2].alloc_exclusive)).unwrap();
+        assert_eq!((outer_alloc.count, outer_alloc.allocated_bytes, outer_alloc.freed_bytes), (1, 100, 100));
+        assert_eq!((inner_alloc.count, inner_alloc.allocated_bytes, inner_alloc.freed_bytes), (1, 1000, 1000));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn page_faults_include_children() {
+        assert!(GLOBAL_PROFILER.enable_page_faults());
+        let outer = ProfileBlock::new("faults outer", 1, 0);
+        let inner = ProfileBlock::new("faults inner", 2, 0);
+        // Large enough to be freshly mapped, so touching it faults
+        let mut pages = std::hint::black_box(vec![0u8; 1 << 20]);
+        pages.iter_mut().step_by(4096).for_each(|byte| *byte = 1);
+        drop(inner);
+        drop(outer);
+        drop(pages);
+
+        let (outer, inner) = with_thread_profile(|thread_profile| (thread_profile.profiles[1], thread_profile.profiles[2])).unwrap();
+        assert!(inner.faults_inclusive.total() > 0);
+        assert_eq!(inner.faults_inclusive, inner.faults_exclusive);
+        assert_eq!(outer.faults_inclusive, outer.faults_exclusive.wrapping_add(&inner.faults_inclusive));
+    }
+
+    fn recurse(depth: u32) {
+        let _block = ProfileBlock::new("recurse", 1, 0);
+        if depth > 1 { recurse(depth - 1); }
+    }
+
+    #[test]
+    fn direct_recursion_counts_outermost_inclusive() {
+        recurse(4);
+        let profile = with_thread_profile(|thread_profile| thread_profile.profiles[1]).unwrap();
+        assert_eq!((profile.invocations, profile.max_depth, profile.open_count), (4, 4, 0));
+        // Nested instances neither double count inclusive time nor lose exclusive time
+        assert_eq!(profile.elapsed_inclusive, profile.elapsed_exclusive);
+    }
+
+    fn ping(depth: u32) {
+        let _block = ProfileBlock::new("ping", 1, 0);
+        pong(depth);
+    }
+
+    fn pong(depth: u32) {
+        let _block = ProfileBlock::new("pong", 2, 0);
+        if depth > 1 { ping(depth - 1); }
+    }
+
+    #[test]
+    fn indirect_recursion_counts_outermost_inclusive() {
+        ping(2);
+        let (ping, pong) = with_thread_profile(|thread_profile| (thread_profile.profiles[1], thread_profile.profiles[2])).unwrap();
+        assert_eq!((ping.invocations, ping.max_depth), (2, 2));
+        assert_eq!((pong.invocations, pong.max_depth), (2, 2));
+        assert_eq!(ping.elapsed_exclusive + pong.elapsed_exclusive, ping.elapsed_inclusive);
+        assert!(pong.elapsed_inclusive <= ping.elapsed_inclusive);
+    }
+
+    #[test]
+    fn call_tree_collapses_recursion() {
+        // Application -> a -> b -> a -> c
+        let mut tree = CallTree::default();
+        let outer_a = tree.child(0, 1);
+        let b = tree.child(outer_a, 2);
+        let inner_a = tree.child(b, 1);
+        let c = tree.child(inner_a, 3);
+        for (node, exclusive, inclusive) in [(outer_a, 10, 100), (b, 20, 90), (inner_a, 30, 70), (c, 40, 40)] {
+            tree.nodes[node].elapsed_exclusive = exclusive;
+            tree.nodes[node].elapsed_inclusive = inclusive;
+            tree.nodes[node].invocations = 1;
+        }
+
+        let collapsed = tree.collapse_recursion();
+        let node = |profile_index: usize| collapsed.nodes.iter().find(|node| node.profile_index == profile_index).unwrap();
+        let a = node(1);
+        assert_eq!((a.elapsed_exclusive, a.elapsed_inclusive, a.invocations, a.recursive_invocations), (40, 100, 2, 1));
+        assert_eq!(collapsed.nodes[node(2).parent].profile_index, 1);
+        // c was opened under the nested a, so it moves up under the collapsed a
+        assert_eq!(collapsed.nodes[node(3).parent].profile_index, 1);
+        assert_eq!(collapsed.nodes.len(), 4);
+    }
+
+    #[test]
+    fn folded_stacks_follow_call_tree() {
+        // Application -> parse -> read;file, Application -> write -> read;file
+        let mut tree = CallTree::default();
+        let parse = tree.child(0, 1);
+        let parse_read = tree.child(parse, 2);
+        let write = tree.child(0, 3);
+        let write_read = tree.child(write, 2);
+        for (node, exclusive, inclusive) in [(0, 5, 100), (parse, 10, 60), (parse_read, 50, 50), (write, 0, 35), (write_read, 35, 35)] {
+            tree.nodes[node].elapsed_exclusive = exclusive;
+            tree.nodes[node].elapsed_inclusive = inclusive;
+        }
+        let profiles: Vec<ProfileAnchor> = ["Application", "parse", "read;file", "write"].iter()
+            .map(|tag| ProfileAnchor { tag, ..Default::default() })
+            .collect();
+
+        let path = std::env::temp_dir().join(format!("folded_stacks_{}.txt", std::process::id()));
+        tree.write_folded(path.to_str().unwrap(), &profiles).unwrap();
+        let folded = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        // write has no exclusive time of its own, ';' inside a tag would split the frame
+        assert_eq!(folded.lines().collect::<Vec<_>>(), vec!["Application 5", "Application;parse 10", "Application;parse;read,file 50", "Application;write;read,file 35"]);
+    }
+
+    #[test]
+    fn sampled_blocks_count_every_invocation() {
+        // Only sampled blocks read self_overhead, and no other unit test opens one or calibrates. The call
+        // tree and block stats only add to this thread's tables in the other tests.
+        let self_overhead = GLOBAL_PROFILER.self_overhead.swap(1, Ordering::Relaxed);
+        GLOBAL_PROFILER.call_tree_enabled.store(true, Ordering::Relaxed);
+        GLOBAL_PROFILER.block_stats_enabled.store(true, Ordering::Relaxed);
+        let timed = (0..10).filter(|_| ProfileBlock::sampled("sampled", 1, 8, 4).is_some()).count();
+        GLOBAL_PROFILER.block_stats_enabled.store(false, Ordering::Relaxed);
+        GLOBAL_PROFILER.call_tree_enabled.store(false, Ordering::Relaxed);
+        GLOBAL_PROFILER.self_overhead.store(self_overhead, Ordering::Relaxed);
+        assert_eq!(timed, 3); // Invocations 0, 4 and 8
+
+        let (profile, stats, node) = with_thread_profile(|thread_profile| {
+            let call_tree = &mut thread_profile.call_tree;
+            let node = call_tree.child(0, 1);
+            let node = call_tree.nodes[node];
+            (thread_profile.profiles[1], thread_profile.block_stats[1], node)
+        }).unwrap();
+        assert_eq!((profile.invocations, profile.untimed_invocations, profile.processed_byte_count, profile.sample_period), (10, 7, 80, 4));
+        // Block stats keep the measured clocks. Each timed block of `elapsed` clocks stands in for 3 untimed
+        // ones of `elapsed - self_overhead`, so the 3 blocks sum to 4 * (their elapsed clocks) - 3 * 3 * self_overhead.
+        assert_eq!(stats.count, 3);
+        assert_eq!(profile.elapsed_exclusive, 4 * stats.sum - 9);
+        assert_eq!(profile.elapsed_inclusive, profile.elapsed_exclusive);
+        // The call tree counts the real calls like the anchor, with the same extrapolated clocks
+        assert_eq!((node.invocations, node.elapsed_exclusive, node.elapsed_inclusive), (10, profile.elapsed_exclusive, profile.elapsed_inclusive));
+    }
+
+    #[test]
+    fn overhead_subtraction() {
+        let overhead = ProfilerOverhead { block_overhead: 30, self_overhead: 10 };
+        // 2 invocations, each with 3 direct children that have 1 child of their own
+        let mut profile = ProfileAnchor {
+            elapsed_exclusive: 1_000, elapsed_inclusive: 5_000, invocations: 2,
+            child_invocations: 6, descendant_invocations: 12, ..Default::default()
+        };
+        profile.subtract_overhead(&overhead);
+        assert_eq!(profile.elapsed_exclusive, 1_000 - 2 * 10 - 6 * 20);
+        assert_eq!(profile.elapsed_inclusive, 5_000 - 2 * 10 - 12 * 30);
+
+        let mut tiny = ProfileAnchor { elapsed_exclusive: 5, elapsed_inclusive: 5, invocations: 1, ..Default::default() };
+        tiny.subtract_overhead(&overhead);
+        assert_eq!((tiny.elapsed_exclusive, tiny.elapsed_inclusive), (0, 0));
+    }
+}
@@ -0,0 +1,224 @@
+// Transport for live profiler results. A profiled process publishes a snapshot of its anchor table
+// every interval to every connected client, as a csv ProfileReport (see report.rs) ended by an empty
+// line. Kept free of the `profile` feature so clients (e.g. profiler-top) do not need the profiler.
+//
+// Addresses are either "unix:<path>" for a Unix domain socket or "<host>:<port>" for a TCP port,
+// which must resolve to a loopback address as the results are not meant to leave the machine.
+
+use std::io::{BufRead, Error, ErrorKind, Read, Result, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::time::Duration;
+
+use crate::report::ProfileReport;
+
+pub const LIVE_ADDRESS_ENV: &str = "PROFILER_LIVE";
+pub const DEFAULT_LIVE_INTERVAL: Duration = Duration::from_secs(1);
+pub const MIN_LIVE_INTERVAL: Duration = Duration::from_millis(10); // Shorter intervals are raised to this
+const WRITE_TIMEOUT: Duration = Duration::from_millis(100);
+
+pub enum LiveStream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream)
+}
+
+pub enum LiveListener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener)
+}
+
+enum LiveAddress<'a> {
+    Tcp(&'a str),
+    Unix(&'a str)
+}
+
+fn parse_address(address: &str) -> Result<LiveAddress<'_>> {
+    if let Some(path) = address.strip_prefix("unix:") {
+        if !cfg!(unix) { return Err(Error::new(ErrorKind::Unsupported, "ERROR: Unix domain sockets are not available on this platform.")); }
+        return Ok(LiveAddress::Unix(path));
+    }
+    let mut socket_addrs = address.to_socket_addrs()?.peekable();
+    if socket_addrs.peek().is_none() || !socket_addrs.all(|socket_addr| socket_addr.ip().is_loopback()) {
+        return Err(Error::new(ErrorKind::InvalidInput, format!("ERROR: Live profiler address {} is not a loopback address.", address)));
+    }
+    Ok(LiveAddress::Tcp(address))
+}
+
+pub fn connect(address: &str) -> Result<LiveStream> {
+    match parse_address(address)? {
+        LiveAddress::Tcp(address) => Ok(LiveStream::Tcp(TcpStream::connect(address)?)),
+        #[cfg(unix)]
+        LiveAddress::Unix(path) => Ok(LiveStream::Unix(UnixStream::connect(path)?)),
+        #[cfg(not(unix))]
+        LiveAddress::Unix(_) => unreachable!()
+    }
+}
+
+impl LiveListener {
+    // Does not block on accept. A socket file left behind by an earlier run at the same path is replaced.
+    pub fn bind(address: &str) -> Result<LiveListener> {
+        let listener = match parse_address(address)? {
+            LiveAddress::Tcp(address) => LiveListener::Tcp(TcpListener::bind(address)?),
+            #[cfg(unix)]
+            LiveAddress::Unix(path) => {
+                use std::os::unix::fs::FileTypeExt;
+                if std::fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+                    std::fs::remove_file(path)?;
+                }
+                LiveListener::Unix(UnixListener::bind(path)?)
+            },
+            #[cfg(not(unix))]
+            LiveAddress::Unix(_) => unreachable!()
+        };
+        match &listener {
+            LiveListener::Tcp(listener) => listener.set_nonblocking(true)?,
+            #[cfg(unix)]
+            LiveListener::Unix(listener) => listener.set_nonblocking(true)?
+        }
+        Ok(listener)
+    }
+
+    // The address clients should connect to, with the port filled in when binding port 0
+    pub fn local_address(&self) -> Result<String> {
+        match self {
+            LiveListener::Tcp(listener) => Ok(listener.local_addr()?.to_string()),
+            #[cfg(unix)]
+            LiveListener::Unix(listener) => {
+                let local_addr = listener.local_addr()?;
+                let path = local_addr.as_pathname().ok_or_else(|| Error::new(ErrorKind::InvalidInput, "ERROR: Unnamed unix socket."))?;
+                Ok(format!("unix:{}", path.display()))
+            }
+        }
+    }
+
+    // Connections waiting to be accepted, returned in blocking mode with a write timeout so a client
+    // that stops reading fails the write once its socket buffer fills, rather than stall the writer
+    pub fn accept_pending(&self) -> Vec<LiveStream> {
+        let mut streams = Vec::new();
+        loop {
+            let stream = match self {
+                LiveListener::Tcp(listener) => listener.accept().map(|(stream, _)| LiveStream::Tcp(stream)),
+                #[cfg(unix)]
+                LiveListener::Unix(listener) => listener.accept().map(|(stream, _)| LiveStream::Unix(stream))
+            };
+            match stream {
+                Ok(stream) if stream.set_nonblocking(false).is_ok() && stream.set_write_timeout(Some(WRITE_TIMEOUT)).is_ok() => streams.push(stream),
+                Ok(_) => {},
+                Err(_) => return streams
+            }
+        }
+    }
+}
+
+impl LiveStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> Result<()> {
+        match self {
+            LiveStream::Tcp(stream) => stream.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            LiveStream::Unix(stream) => stream.set_nonblocking(nonblocking)
+        }
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        match self {
+            LiveStream::Tcp(stream) => stream.set_write_timeout(timeout),
+            #[cfg(unix)]
+            LiveStream::Unix(stream) => stream.set_write_timeout(timeout)
+        }
+    }
+}
+
+impl Read for LiveStream {
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize> {
+        match self {
+            LiveStream::Tcp(stream) => stream.read(buffer),
+            #[cfg(unix)]
+            LiveStream::Unix(stream) => stream.read(buffer)
+        }
+    }
+}
+
+impl Write for LiveStream {
+    fn write(&mut self, buffer: &[u8]) -> Result<usize> {
+        match self {
+            LiveStream::Tcp(stream) => stream.write(buffer),
+            #[cfg(unix)]
+            LiveStream::Unix(stream) => stream.write(buffer)
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        match self {
+            LiveStream::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            LiveStream::Unix(stream) => stream.flush()
+        }
+    }
+}
+
+pub fn write_frame(writer: &mut impl Write, report: &ProfileReport) -> Result<()> {
+    let mut frame = report.to_csv();
+    frame.push('\n');
+    writer.write_all(frame.as_bytes())?;
+    writer.flush()
+}
+
+// Returns None once the publisher closed the connection.
+// Frames for a session without any closed blocks yet have no rows, only cpu_freq and the totals.
+pub fn read_frame(reader: &mut impl BufRead) -> Result<Option<ProfileReport>> {
+    let mut frame = String::new();
+    loop {
+        let line_start = frame.len();
+        if reader.read_line(&mut frame)? == 0 {
+            return if frame.trim().is_empty() { Ok(None) } else { Err(Error::new(ErrorKind::UnexpectedEof, "ERROR: Live profiler frame was cut off.")) };
+        }
+        if frame[line_start..].trim().is_empty() {
+            return ProfileReport::from_csv(&frame).map(Some);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::ProfileReportEntry;
+
+    #[test]
+    fn frames_round_trip() {
+        let report = ProfileReport {
+            cpu_freq: 2_000_000_000, total_clocks: 40_000_000, clocks_profiled: 39_000_000,
+            entries: vec![ProfileReportEntry::new("fs::read", 1, 2_000_000, 2_000_000, 1_000_000, 2_000_000_000)]
+        };
+        let mut stream = Vec::new();
+        write_frame(&mut stream, &report).unwrap();
+        write_frame(&mut stream, &ProfileReport::default()).unwrap();
+
+        let mut reader = std::io::Cursor::new(stream);
+        assert_eq!(read_frame(&mut reader).unwrap(), Some(report));
+        assert_eq!(read_frame(&mut reader).unwrap(), Some(ProfileReport::default()));
+        assert_eq!(read_frame(&mut reader).unwrap(), None);
+    }
+
+    #[test]
+    fn stalled_clients_time_out() {
+        let listener = LiveListener::bind("127.0.0.1:0").unwrap();
+        let _client = connect(&listener.local_address().unwrap()).unwrap();
+        let mut streams = Vec::new();
+        while streams.is_empty() { streams = listener.accept_pending(); }
+
+        // The client never reads, so writes start failing once the socket buffers are full
+        let chunk = vec![0u8; 1 << 16];
+        let error = (0..4096).find_map(|_| streams[0].write_all(&chunk).err()).expect("Writes to a stalled client never failed.");
+        assert!(matches!(error.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut), "{:?}", error);
+    }
+
+    #[test]
+    fn tcp_addresses_must_be_loopback() {
+        assert!(parse_address("127.0.0.1:0").is_ok());
+        assert!(parse_address("[::1]:0").is_ok());
+        assert_eq!(parse_address("0.0.0.0:0").err().map(|e| e.kind()), Some(ErrorKind::InvalidInput));
+    }
+}
@@ -0,0 +1,257 @@
+// Machine-readable form of the profiler results. Kept free of the `profile` feature so that
+// tools reading saved reports (e.g. profile_diff) do not need to build the profiler itself.
+
+use std::io::{Error, ErrorKind::InvalidData, Result};
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ProfileReportEntry {
+    pub tag: String,
+    pub invocations: u64,
+    pub elapsed_exclusive: u64, // Does not include children
+    pub elapsed_inclusive: u64, // Does include children
+    pub processed_byte_count: u64,
+    pub bandwidth: f64, // Bytes per second over elapsed_inclusive, 0 when no bytes were processed
+    pub sample_period: u64, // > 1 when clocks were extrapolated from 1 in sample_period invocations
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ProfileReport {
+    pub cpu_freq: u64,
+    pub total_clocks: u64,
+    pub clocks_profiled: u64,
+    pub entries: Vec<ProfileReportEntry>,
+}
+
+pub const REPORT_PATH_ENV: &str = "PROFILER_REPORT";
+
+// The csv starts with the report-wide values under their own header, then one row per entry
+pub const CSV_REPORT_HEADER: &str = "cpu_freq,total_clocks,clocks_profiled";
+pub const CSV_HEADER: &str = "tag,invocations,elapsed_exclusive,elapsed_inclusive,processed_byte_count,bandwidth,sample_period";
+
+impl ProfileReportEntry {
+    pub fn new(tag: &str, invocations: u64, elapsed_exclusive: u64, elapsed_inclusive: u64, processed_byte_count: u64, cpu_freq: u64) -> Self {
+        let bandwidth = if processed_byte_count == 0 || elapsed_inclusive == 0 { 0.0 } else {
+            processed_byte_count as f64 / (elapsed_inclusive as f64 / cpu_freq as f64)
+        };
+        ProfileReportEntry { tag: String::from(tag), invocations, elapsed_exclusive, elapsed_inclusive, processed_byte_count, bandwidth, sample_period: 0 }
+    }
+}
+
+impl ProfileReport {
+    pub fn total_millisecs(&self) -> f64 { self.total_clocks as f64 / self.cpu_freq as f64 * 1000.0 }
+
+    pub fn entry(&self, tag: &str) -> Option<&ProfileReportEntry> { self.entries.iter().find(|entry| entry.tag == tag) }
+
+    pub fn to_json(&self) -> String {
+        let mut json = String::from("{\n");
+        json.push_str(&format!("  \"cpu_freq\": {},\n", self.cpu_freq));
+        json.push_str(&format!("  \"total_clocks\": {},\n", self.total_clocks));
+        json.push_str(&format!("  \"total_millisecs\": {:.6},\n", self.total_millisecs()));
+        json.push_str(&format!("  \"clocks_profiled\": {},\n", self.clocks_profiled));
+        json.push_str("  \"entries\": [");
+        for (i, entry) in self.entries.iter().enumerate() {
+            json.push_str(if i == 0 { "\n" } else { ",\n" });
+            json.push_str(&format!(
+                "    {{ \"tag\": \"{}\", \"invocations\": {}, \"elapsed_exclusive\": {}, \"elapsed_inclusive\": {}, \"processed_byte_count\": {}, \"bandwidth\": {:.3}, \"sample_period\": {} }}",
+                json_escape(&entry.tag), entry.invocations, entry.elapsed_exclusive, entry.elapsed_inclusive, entry.processed_byte_count, entry.bandwidth, entry.sample_period
+            ));
+        }
+        json.push_str("\n  ]\n}\n");
+        json
+    }
+
+    pub fn to_csv(&self) -> String {
+        let mut csv = format!("{}\n{},{},{}\n{}\n", CSV_REPORT_HEADER, self.cpu_freq, self.total_clocks, self.clocks_profiled, CSV_HEADER);
+        for entry in self.entries.iter() {
+            csv.push_str(&format!("{},{},{},{},{},{:.3},{}\n",
+                csv_escape(&entry.tag), entry.invocations, entry.elapsed_exclusive, entry.elapsed_inclusive, entry.processed_byte_count, entry.bandwidth, entry.sample_period));
+        }
+        csv
+    }
+
+    pub fn from_csv(csv: &str) -> Result<ProfileReport> {
+        let mut lines = csv.lines().filter(|line| !line.trim().is_empty());
+        if lines.next().map(str::trim) != Some(CSV_REPORT_HEADER) {
+            return Err(Error::new(InvalidData, "ERROR: Profile report csv is missing its report header."));
+        }
+        let line = lines.next().unwrap_or_default();
+        let values = line.split(',').map(|field| parse_csv_u64(field, line)).collect::<Result<Vec<u64>>>()?;
+        let [cpu_freq, total_clocks, clocks_profiled] = values[..] else {
+            return Err(Error::new(InvalidData, format!("ERROR: Expected 3 fields in profile report csv totals: {}", line)));
+        };
+        let mut report = ProfileReport { cpu_freq, total_clocks, clocks_profiled, entries: Vec::new() };
+        if lines.next().map(str::trim) != Some(CSV_HEADER) {
+            return Err(Error::new(InvalidData, "ERROR: Profile report csv is missing its header."));
+        }
+
+        for line in lines {
+            let (tag, rest) = csv_split_tag(line)?;
+            let fields: Vec<&str> = rest.split(',').collect();
+            let [invocations, elapsed_exclusive, elapsed_inclusive, processed_byte_count, bandwidth, sample_period] = fields[..] else {
+                return Err(Error::new(InvalidData, format!("ERROR: Expected 7 fields in profile report csv row: {}", line)));
+            };
+            report.entries.push(ProfileReportEntry {
+                tag,
+                invocations: parse_csv_u64(invocations, line)?,
+                elapsed_exclusive: parse_csv_u64(elapsed_exclusive, line)?,
+                elapsed_inclusive: parse_csv_u64(elapsed_inclusive, line)?,
+                processed_byte_count: parse_csv_u64(processed_byte_count, line)?,
+                bandwidth: bandwidth.trim().parse::<f64>().map_err(|e| Error::new(InvalidData, format!("ERROR: {} in profile report csv row: {}", e, line)))?,
+                sample_period: parse_csv_u64(sample_period, line)?,
+            });
+        }
+        Ok(report)
+    }
+
+    // Format is picked by extension: ".csv" writes csv, anything else writes json
+    pub fn write_to_path(&self, path: &str) -> Result<()> {
+        let contents = if path.ends_with(".csv") { self.to_csv() } else { self.to_json() };
+        std::fs::write(path, contents)
+    }
+}
+
+pub fn json_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c)
+        }
+    }
+    escaped
+}
+
+// Decodes every escape json_escape emits, plus the rest of the JSON escapes, in a single pass.
+// Unpaired surrogates and malformed escapes become U+FFFD.
+pub fn json_unescape(s: &str) -> String {
+    let mut unescaped = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('b') => unescaped.push('\u{8}'),
+            Some('f') => unescaped.push('\u{c}'),
+            Some('n') => unescaped.push('\n'),
+            Some('r') => unescaped.push('\r'),
+            Some('t') => unescaped.push('\t'),
+            Some('u') => {
+                let high = json_code_unit(&mut chars);
+                // Characters outside the BMP are escaped as a surrogate pair
+                let low = match high {
+                    Some(0xD800..=0xDBFF) if chars.as_str().starts_with("\\u") => {
+                        chars.nth(1); // Skips the \u in front of the low surrogate
+                        json_code_unit(&mut chars)
+                    },
+                    _ => None
+                };
+                let code_units = high.into_iter().chain(low);
+                unescaped.extend(char::decode_utf16(code_units).map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER)));
+                if high.is_none() { unescaped.push(char::REPLACEMENT_CHARACTER); }
+            },
+            Some(c) => unescaped.push(c), // '"', '\\' and '/'
+            None => unescaped.push(char::REPLACEMENT_CHARACTER)
+        }
+    }
+    unescaped
+}
+
+fn json_code_unit(chars: &mut impl Iterator<Item = char>) -> Option<u16> {
+    u16::from_str_radix(&chars.take(4).collect::<String>(), 16).ok()
+}
+
+fn parse_csv_u64(field: &str, line: &str) -> Result<u64> {
+    field.trim().parse::<u64>().map_err(|e| Error::new(InvalidData, format!("ERROR: {} in profile report csv row: {}", e, line)))
+}
+
+fn csv_escape(s: &str) -> String {
+    if s.contains([',', '"', '\n']) { format!("\"{}\"", s.replace('"', "\"\"")) } else { String::from(s) }
+}
+
+// Splits the (possibly quoted) tag off the front of a csv row
+fn csv_split_tag(line: &str) -> Result<(String, &str)> {
+    if let Some(quoted) = line.strip_prefix('"') {
+        let mut tag = String::new();
+        let mut chars = quoted.char_indices().peekable();
+        while let Some((i, c)) = chars.next() {
+            if c == '"' {
+                if let Some((_, '"')) = chars.peek() {
+                    tag.push('"');
+                    chars.next();
+                } else {
+                    let rest = quoted[i + 1..].strip_prefix(',').ok_or_else(|| Error::new(InvalidData, "ERROR: Expected ',' after quoted tag in profile report csv."))?;
+                    return Ok((tag, rest));
+                }
+            } else {
+                tag.push(c);
+            }
+        }
+        Err(Error::new(InvalidData, "ERROR: Unterminated quoted tag in profile report csv."))
+    } else {
+        let (tag, rest) = line.split_once(',').ok_or_else(|| Error::new(InvalidData, "ERROR: Expected ',' after tag in profile report csv."))?;
+        Ok((String::from(tag), rest))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_report() -> ProfileReport {
+        ProfileReport {
+            cpu_freq: 2_000_000_000,
+            total_clocks: 40_000_000,
+            clocks_profiled: 39_000_000,
+            entries: vec![
+                ProfileReportEntry::new("fs::read", 1, 2_000_000, 2_000_000, 1_000_000, 2_000_000_000),
+                ProfileReportEntry::new("parse \"quoted\", with comma", 3, 30_000_000, 35_000_000, 0, 2_000_000_000),
+                ProfileReportEntry { sample_period: 64, ..ProfileReportEntry::new("haversine", 640, 1_000_000, 1_000_000, 0, 2_000_000_000) },
+            ]
+        }
+    }
+
+    #[test]
+    fn csv_round_trip() {
+        let report = sample_report();
+        let parsed = ProfileReport::from_csv(&report.to_csv()).expect("Failed to parse report csv.");
+        assert_eq!(parsed, report);
+    }
+
+    #[test]
+    fn csv_round_trip_without_entries() {
+        let report = ProfileReport { entries: Vec::new(), ..sample_report() };
+        let parsed = ProfileReport::from_csv(&report.to_csv()).expect("Failed to parse report csv.");
+        assert_eq!(parsed, report);
+    }
+
+    #[test]
+    fn csv_missing_header() {
+        assert!(ProfileReport::from_csv("fs::read,1,2,3,4,5.0,6,7,8\n").is_err());
+        assert!(ProfileReport::from_csv("cpu_freq,total_clocks,clocks_profiled\n1,2,3\nfs::read,1,2,3,4,5.0,6\n").is_err());
+        assert!(ProfileReport::from_csv(&format!("{}\nfs::read,1,2,3,4,5.0,6\n", CSV_HEADER)).is_err());
+    }
+
+    #[test]
+    fn json_escapes_tags() {
+        let json = sample_report().to_json();
+        assert!(json.contains("\"tag\": \"parse \\\"quoted\\\", with comma\""));
+        assert!(json.contains("\"cpu_freq\": 2000000000"));
+    }
+
+    #[test]
+    fn json_escapes_round_trip() {
+        let tag = "parse \"quoted\" C:\\path\\\\u0041\tend\u{1}";
+        assert_eq!(json_unescape(&json_escape(tag)), tag);
+        assert_eq!(json_unescape("\\u00e9\\/\\ud83d\\ude00"), "\u{e9}/\u{1f600}");
+    }
+
+    #[test]
+    fn bandwidth_uses_inclusive_time() {
+        let entry = ProfileReportEntry::new("tag", 1, 0, 1_000, 500, 1_000);
+        assert_eq!(entry.bandwidth, 500.0); // 500 bytes over 1 second
+    }
+}
@@ -0,0 +1,96 @@
+// tracing_subscriber::Layer that feeds `tracing` spans into the profiler. Every enter/exit of a span
+// becomes a block of the anchor named after the span, so spans land in the same report as the profiler
+// macros. A span entered several times (e.g. a future polled repeatedly) counts one invocation per
+// enter. A `bytes` field on the span is added to the anchor's processed bytes on its first enter.
+// Spans must exit in the reverse order they entered on a thread, which Span::enter and Instrument do.
+// Spans emitted by the profiler itself (profiler_enable_tracing_spans!) are skipped, they already
+// have a block. Without the `profile` feature the layer does nothing.
+
+use tracing::Subscriber;
+use tracing_subscriber::layer::Layer;
+use tracing_subscriber::registry::LookupSpan;
+
+#[cfg(feature = "profile")]
+use std::cell::RefCell;
+#[cfg(feature = "profile")]
+use tracing::field::{Field, Visit};
+#[cfg(feature = "profile")]
+use tracing::span::{Attributes, Id};
+#[cfg(feature = "profile")]
+use tracing_subscriber::layer::Context;
+
+#[cfg(feature = "profile")]
+use crate::{named_anchor, ProfileBlock, TRACING_SPAN_NAME, TRACING_SPAN_TARGET};
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ProfilerLayer;
+
+impl ProfilerLayer {
+    pub fn new() -> Self { ProfilerLayer }
+}
+
+// Stored in the span's extensions when it is created
+#[cfg(feature = "profile")]
+struct SpanAnchor {
+    tag: &'static str,
+    profile_index: usize,
+    byte_count: u64 // Taken by the first enter
+}
+
+#[cfg(feature = "profile")]
+#[derive(Default)]
+struct BytesVisitor(u64);
+
+#[cfg(feature = "profile")]
+impl Visit for BytesVisitor {
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        if field.name() == "bytes" { self.0 = value; }
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        if field.name() == "bytes" { self.0 = value.max(0) as u64; }
+    }
+
+    fn record_debug(&mut self, _field: &Field, _value: &dyn std::fmt::Debug) {}
+}
+
+#[cfg(feature = "profile")]
+thread_local! {
+    // Blocks of the spans currently entered on this thread, innermost last
+    static ENTERED_SPAN_BLOCKS: RefCell<Vec<(Id, ProfileBlock)>> = const { RefCell::new(Vec::new()) };
+}
+
+#[cfg(feature = "profile")]
+impl<S> Layer<S> for ProfilerLayer where S: Subscriber + for<'a> LookupSpan<'a> {
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let metadata = attrs.metadata();
+        if metadata.target() == TRACING_SPAN_TARGET && metadata.name() == TRACING_SPAN_NAME { return; }
+        let Some(span) = ctx.span(id) else { return; };
+
+        let mut bytes = BytesVisitor::default();
+        attrs.record(&mut bytes);
+        let (tag, profile_index) = named_anchor(metadata.name());
+        span.extensions_mut().insert(SpanAnchor { tag, profile_index, byte_count: bytes.0 });
+    }
+
+    fn on_enter(&self, id: &Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else { return; };
+        let mut extensions = span.extensions_mut();
+        let Some(anchor) = extensions.get_mut::<SpanAnchor>() else { return; };
+        let byte_count = std::mem::take(&mut anchor.byte_count);
+        let block = ProfileBlock::new(anchor.tag, anchor.profile_index, byte_count);
+        ENTERED_SPAN_BLOCKS.with(|blocks| blocks.borrow_mut().push((id.clone(), block)));
+    }
+
+    fn on_exit(&self, id: &Id, _ctx: Context<'_, S>) {
+        let block = ENTERED_SPAN_BLOCKS.with(|blocks| {
+            let mut blocks = blocks.borrow_mut();
+            let position = blocks.iter().rposition(|(entered_id, _)| entered_id == id)?;
+            Some(blocks.remove(position).1)
+        });
+        drop(block);
+    }
+}
+
+#[cfg(not(feature = "profile"))]
+impl<S> Layer<S> for ProfilerLayer where S: Subscriber + for<'a> LookupSpan<'a> {}
@@ -0,0 +1,21 @@
+#![cfg(feature = "profile")]
+
+use profiler::*;
+
+#[test]
+fn tables_grow_past_registered_anchors() {
+    GLOBAL_PROFILER.init();
+    // The calling thread's table is created here, before the anchors below are registered
+    time_block!("first");
+
+    let indices: Vec<usize> = (0..10_000).map(|_| __GLOBAL_PROFILER__COUNTER__()).collect();
+    assert!(indices.windows(2).all(|pair| pair[1] == pair[0] + 1));
+    let last_index = *indices.last().unwrap();
+    drop(ProfileBlock::new("last", last_index, 0));
+    std::thread::spawn(move || drop(ProfileBlock::new("last", last_index, 0))).join().unwrap();
+    drop(ProfileBlock::new("second to last", last_index - 1, 0));
+
+    let report = GLOBAL_PROFILER.report();
+    assert_eq!(report.entry("last").map(|entry| entry.invocations), Some(2));
+    assert_eq!(report.entry("second to last").map(|entry| entry.invocations), Some(1));
+}
@@ -0,0 +1,24 @@
+// Helpers shared by the integration tests.
+//
+// The profiler, its options and its sessions are process wide, and every file under tests/ builds
+// into its own process, so each file keeps to a single #[test] rather than racing other tests over
+// GLOBAL_PROFILER.
+#![allow(dead_code)] // each test file uses a subset
+
+use profiler::*;
+
+// Closes `iterations` blocks tagged "work" on the calling thread
+pub fn work(iterations: u32) {
+    for i in 0..iterations {
+        time_block!("work");
+        std::hint::black_box(i);
+    }
+}
+
+pub fn entry(tag: &str) -> ProfileReportEntry {
+    GLOBAL_PROFILER.report().entry(tag).cloned().unwrap_or_default()
+}
+
+pub fn invocations(tag: &str) -> u64 {
+    entry(tag).invocations
+}
@@ -0,0 +1,32 @@
+#![cfg(feature = "profile")]
+
+use profiler::*;
+
+fn parse() {
+    time_function!();
+    for _ in 0..2 {
+        time_block!("read");
+        std::hint::black_box(0);
+    }
+}
+
+#[test]
+fn folded_stacks_follow_nesting() {
+    GLOBAL_PROFILER.init();
+    let path = std::env::temp_dir().join(format!("folded_stacks_{}.txt", std::process::id()));
+    let path = path.to_str().unwrap();
+    GLOBAL_PROFILER.enable_folded_stacks(path);
+
+    parse();
+    {
+        time_block!("write");
+        time_block!("read");
+    }
+    GLOBAL_PROFILER.print_and_deinit();
+
+    let folded = std::fs::read_to_string(path).unwrap();
+    std::fs::remove_file(path).unwrap();
+    let stacks: Vec<&str> = folded.lines().map(|line| line.rsplit_once(' ').unwrap().0).collect();
+    assert_eq!(stacks, vec!["Application", "Application;parse", "Application;parse;read", "Application;write", "Application;write;read"]);
+    assert!(folded.lines().all(|line| line.rsplit_once(' ').unwrap().1.parse::<u64>().is_ok_and(|clocks| clocks > 0)));
+}
@@ -0,0 +1,59 @@
+#![cfg(feature = "profile")]
+
+use std::io::BufReader;
+use std::time::{Duration, Instant};
+
+mod common;
+
+use common::work;
+use profiler::*;
+
+// Reads frames until one shows at least `invocations` of "work" from every thread
+fn wait_for_invocations(address: &str, invocations: u64) {
+    let mut reader = BufReader::new(live::connect(address).unwrap());
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while Instant::now() < deadline {
+        let report = live::read_frame(&mut reader).unwrap().expect("Publisher closed the connection.");
+        if report.entry("work").is_some_and(|entry| entry.invocations >= invocations) { return; }
+    }
+    panic!("No live frame with {} invocations from {}", invocations, address);
+}
+
+#[test]
+fn publishes_running_threads() {
+    GLOBAL_PROFILER.init();
+    let tcp_address = GLOBAL_PROFILER.publish_live("127.0.0.1:0", Duration::from_millis(10)).unwrap();
+
+    // Blocks from a thread that is still running, it keeps closing blocks so its table gets refreshed
+    let (done_sender, done_receiver) = std::sync::mpsc::channel::<()>();
+    let thread = std::thread::spawn(move || {
+        while done_receiver.try_recv().is_err() {
+            work(10);
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    });
+    wait_for_invocations(&tcp_address, 100);
+    // Publishing on an address already served keeps the listener and its clients
+    assert_eq!(GLOBAL_PROFILER.publish_live(&tcp_address, Duration::from_millis(10)).unwrap(), tcp_address);
+
+    // A zero interval is raised to the minimum rather than publishing as fast as the loop can spin
+    assert_eq!(GLOBAL_PROFILER.publish_live(&tcp_address, Duration::ZERO).unwrap(), tcp_address);
+    let mut reader = BufReader::new(live::connect(&tcp_address).unwrap());
+    live::read_frame(&mut reader).unwrap().expect("Publisher closed the connection.");
+    let frames_start = Instant::now();
+    for _ in 0..5 {
+        live::read_frame(&mut reader).unwrap().expect("Publisher closed the connection.");
+    }
+    assert!(frames_start.elapsed() >= live::MIN_LIVE_INTERVAL * 4, "5 frames in {:?}", frames_start.elapsed());
+
+    #[cfg(unix)]
+    {
+        let path = std::env::temp_dir().join(format!("profiler_live_{}.sock", std::process::id()));
+        let unix_address = GLOBAL_PROFILER.publish_live(&format!("unix:{}", path.display()), Duration::from_millis(10)).unwrap();
+        wait_for_invocations(&unix_address, 200);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    done_sender.send(()).unwrap();
+    thread.join().unwrap();
+}
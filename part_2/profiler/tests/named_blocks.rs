@@ -0,0 +1,39 @@
+#![cfg(feature = "profile")]
+
+use std::fmt;
+
+use profiler::*;
+
+// Fails the test if a stopped profiler formats its tag
+struct Unformattable;
+
+impl fmt::Display for Unformattable {
+    fn fmt(&self, _: &mut fmt::Formatter) -> fmt::Result { panic!("Formatted a tag while stopped") }
+}
+
+#[test]
+fn runtime_tags_past_capacity_share_an_anchor() {
+    GLOBAL_PROFILER.init();
+    profiler_stop!();
+    {
+        time_named_block!("stopped {}", Unformattable);
+    }
+    profiler_start!();
+
+    let iteration_count = NAMED_ANCHOR_CAPACITY + 10;
+    for iteration in 0..iteration_count {
+        time_named_block!("iteration {}", iteration);
+    }
+    // Tags that got their own anchor keep it once the capacity is spent
+    {
+        time_named_block!("iteration {}", 0);
+    }
+
+    let report = GLOBAL_PROFILER.report();
+    let invocations = |tag: &str| report.entry(tag).map_or(0, |entry| entry.invocations);
+    assert_eq!(invocations("iteration 0"), 2);
+    assert_eq!(invocations(&format!("iteration {}", NAMED_ANCHOR_CAPACITY - 1)), 1);
+    assert_eq!(invocations(&format!("iteration {}", NAMED_ANCHOR_CAPACITY)), 0);
+    assert_eq!(invocations("[other named blocks]"), 10);
+    assert_eq!(report.entries.len(), NAMED_ANCHOR_CAPACITY + 1);
+}
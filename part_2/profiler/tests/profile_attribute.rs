@@ -0,0 +1,55 @@
+#![cfg(feature = "profile")]
+
+use profiler::profile;
+
+#[profile]
+fn early_return(n: u64) -> u64 {
+    if n == 0 { return 0; }
+    n * 2
+}
+
+#[profile(bytes = values.len() as u64 * 8)]
+fn sum_values(values: &[u64]) -> u64 {
+    values.iter().sum()
+}
+
+#[profile]
+fn largest<T: PartialOrd + Copy>(values: &[T]) -> Option<T> {
+    values.iter().copied().reduce(|a, b| if b > a { b } else { a })
+}
+
+// The generated bindings must not shadow the function's own names
+#[profile]
+fn shadowing(__profile_block: u32, __profiler_byte_count: &str) -> usize {
+    __profile_block as usize + __profiler_byte_count.len()
+}
+
+struct Counter { count: u64 }
+
+impl Counter {
+    #[profile]
+    fn increment(&mut self) -> u64 {
+        self.count += 1;
+        self.count
+    }
+}
+
+#[test]
+fn profile_attribute_records_blocks() {
+    assert_eq!(early_return(0), 0);
+    assert_eq!(early_return(4), 8);
+    assert_eq!(sum_values(&[1, 2, 3]), 6);
+    assert_eq!(largest(&[1, 5, 3]), Some(5));
+    assert_eq!(largest(&[1.0, -2.0]), Some(1.0));
+    assert_eq!(shadowing(1, "ab"), 3);
+    let mut counter = Counter { count: 0 };
+    counter.increment();
+    assert_eq!(counter.increment(), 2);
+
+    let report = profiler::GLOBAL_PROFILER.report();
+    let invocations = |tag: &str| report.entry(tag).map_or(0, |entry| entry.invocations);
+    assert_eq!(invocations("early_return"), 2);
+    assert_eq!(invocations("largest"), 2); // Both instantiations share one anchor
+    assert_eq!(invocations("increment"), 2);
+    assert_eq!(report.entry("sum_values").map(|entry| entry.processed_byte_count), Some(24));
+}
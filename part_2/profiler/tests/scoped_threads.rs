@@ -0,0 +1,36 @@
+#![cfg(feature = "profile")]
+
+mod common;
+
+use std::sync::mpsc;
+
+use common::{invocations, work};
+use profiler::*;
+
+#[test]
+fn threads_are_reported_while_running_and_right_after_exit() {
+    GLOBAL_PROFILER.init();
+
+    // A scope can return before its threads' thread locals are destroyed
+    for scope_index in 1..=100 {
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| work(10));
+            }
+        });
+        assert_eq!(invocations("work"), scope_index * 40, "Scope {} lost worker blocks", scope_index);
+    }
+
+    let (done_sender, done_receiver) = mpsc::channel();
+    let (exit_sender, exit_receiver) = mpsc::channel::<()>();
+    let running = std::thread::spawn(move || {
+        work(5);
+        done_sender.send(()).unwrap();
+        exit_receiver.recv().unwrap();
+    });
+    done_receiver.recv().unwrap();
+    assert_eq!(invocations("work"), 4005);
+    exit_sender.send(()).unwrap();
+    running.join().unwrap();
+    assert_eq!(invocations("work"), 4005);
+}
@@ -0,0 +1,77 @@
+#![cfg(feature = "profile")]
+
+mod common;
+
+use common::{invocations, work};
+use profiler::*;
+
+#[test]
+fn start_stop_reset_snapshot() {
+    GLOBAL_PROFILER.init();
+    work(10);
+    assert_eq!(invocations("work"), 10);
+
+    profiler_stop!();
+    assert!(!GLOBAL_PROFILER.is_recording());
+    let stopped_clocks = GLOBAL_PROFILER.report().total_clocks;
+    work(100);
+    assert_eq!(invocations("work"), 10);
+    assert_eq!(GLOBAL_PROFILER.report().total_clocks, stopped_clocks);
+
+    profiler_start!();
+    work(5);
+    assert_eq!(invocations("work"), 15);
+
+    // Threads that exited before the reset are dropped along with the calling thread's data
+    std::thread::spawn(|| work(7)).join().unwrap();
+    assert_eq!(invocations("work"), 22);
+    profiler_reset!();
+    assert_eq!(invocations("work"), 0);
+
+    // Only the second of three repetitions is recorded
+    profiler_stop!();
+    for repetition in 0..3 {
+        if repetition == 1 { profiler_start!(); }
+        work(4);
+        if repetition == 1 { profiler_stop!(); }
+    }
+    assert_eq!(invocations("work"), 4);
+
+    // A thread that recorded before the reset catches up at its next outermost block
+    profiler_start!();
+    let (reset_sender, reset_receiver) = std::sync::mpsc::channel::<()>();
+    let (worked_sender, worked_receiver) = std::sync::mpsc::channel::<()>();
+    let thread = std::thread::spawn(move || {
+        work(3);
+        worked_sender.send(()).unwrap();
+        reset_receiver.recv().unwrap();
+        work(2);
+    });
+    worked_receiver.recv().unwrap();
+    profiler_reset!();
+    reset_sender.send(()).unwrap();
+    thread.join().unwrap();
+    assert_eq!(invocations("work"), 2);
+
+    // A reset inside open blocks is deferred to the calling thread's next outermost block
+    GLOBAL_PROFILER.enable_call_tree();
+    {
+        work(2);
+        time_block!("outer");
+        profiler_reset!();
+        assert_eq!(invocations("work"), 0);
+        work(3);
+    }
+    assert_eq!(invocations("work"), 0);
+    {
+        time_block!("outer");
+        {
+            time_block!("mid");
+            profiler_reset!();
+        }
+        work(1);
+    }
+    assert_eq!((invocations("outer"), invocations("mid"), invocations("work")), (0, 0, 0));
+    work(4);
+    assert_eq!((invocations("outer"), invocations("work")), (0, 4));
+}
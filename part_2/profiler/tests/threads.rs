@@ -0,0 +1,29 @@
+#![cfg(feature = "profile")]
+
+mod common;
+
+use common::{invocations, work};
+use profiler::*;
+
+fn spawn_worker(name: &str, iterations: u32) -> std::thread::JoinHandle<()> {
+    std::thread::Builder::new().name(String::from(name)).spawn(move || work(iterations)).unwrap()
+}
+
+#[test]
+fn exited_threads_fold_by_name() {
+    GLOBAL_PROFILER.init();
+    work(1);
+    let workers = vec![spawn_worker("worker", 10), spawn_worker("worker", 20), spawn_worker("reader", 5)];
+    workers.into_iter().for_each(|worker| worker.join().unwrap());
+    std::thread::spawn(|| work(3)).join().unwrap();
+    std::thread::spawn(|| work(4)).join().unwrap();
+
+    assert_eq!(invocations("work"), 43);
+
+    let thread_reports = GLOBAL_PROFILER.thread_reports();
+    let breakdown: Vec<(&str, usize, u64)> = thread_reports.iter()
+        .map(|thread| (thread.thread_name.as_str(), thread.thread_count, thread.report.entry("work").map_or(0, |entry| entry.invocations)))
+        .collect();
+    let current_thread = std::thread::current();
+    assert_eq!(breakdown, vec![(current_thread.name().unwrap(), 1, 1), ("worker", 2, 30), ("reader", 1, 5), ("unnamed", 2, 7)]);
+}
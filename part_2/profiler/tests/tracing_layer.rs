@@ -0,0 +1,62 @@
+#![cfg(all(feature = "profile", feature = "tracing"))]
+
+mod common;
+
+use std::sync::{Arc, Mutex};
+
+use common::entry;
+use profiler::*;
+use tracing::field::{Field, Visit};
+use tracing::span::Attributes;
+use tracing::{Id, Subscriber};
+use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+
+// Records the `tag` field of every span the profiler emits
+#[derive(Clone, Default)]
+struct TagRecorder(Arc<Mutex<Vec<String>>>);
+
+struct TagVisitor<'a>(&'a mut Vec<String>);
+
+impl Visit for TagVisitor<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "tag" { self.0.push(String::from(value)); }
+    }
+
+    fn record_debug(&mut self, _field: &Field, _value: &dyn std::fmt::Debug) {}
+}
+
+impl<S: Subscriber> Layer<S> for TagRecorder {
+    fn on_new_span(&self, attrs: &Attributes<'_>, _id: &Id, _ctx: Context<'_, S>) {
+        if attrs.metadata().name() == TRACING_SPAN_NAME {
+            attrs.record(&mut TagVisitor(&mut self.0.lock().unwrap()));
+        }
+    }
+}
+
+#[test]
+fn spans_and_blocks_share_sections() {
+    GLOBAL_PROFILER.init();
+    let recorder = TagRecorder::default();
+    let subscriber = tracing_subscriber::registry().with(ProfilerLayer::new()).with(recorder.clone());
+
+    tracing::subscriber::with_default(subscriber, || {
+        // Spans become anchors named after the span, bytes are counted once per span
+        let span = tracing::info_span!("decode", bytes = 100u64);
+        for _ in 0..2 {
+            let _entered = span.enter();
+            let _inner = tracing::info_span!("decode inner").entered();
+        }
+        let decode = entry("decode");
+        assert_eq!((decode.invocations, decode.processed_byte_count), (2, 100));
+        assert_eq!(entry("decode inner").invocations, 2);
+        assert!(decode.elapsed_inclusive >= entry("decode inner").elapsed_inclusive);
+
+        // Profile blocks emit spans, which the layer does not count a second time
+        assert!(GLOBAL_PROFILER.enable_tracing_spans());
+        {
+            time_block!("encode");
+        }
+        assert_eq!(entry("encode").invocations, 1);
+        assert_eq!(*recorder.0.lock().unwrap(), vec![String::from("encode")]);
+    });
+}
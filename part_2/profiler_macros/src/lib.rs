@@ -0,0 +1,70 @@
+// Attribute form of time_function!/time_bandwidth_function!, re-exported as profiler::profile.
+//
+//      #[profile]
+//      #[profile(bytes = input.len() as u64)]
+//
+// The whole body is wrapped in one profiler block tagged with the function name, so it can sit on
+// methods and generic functions (one anchor shared by every instantiation), and early returns are
+// timed like any other exit. The byte count expression is evaluated before the body runs.
+// Without the profile feature the byte count is type checked behind `if false` like the other macros'
+// arguments, but never evaluated, and the body is left untouched.
+
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use quote::{quote, quote_spanned};
+use syn::{parse_macro_input, Expr, ItemFn};
+
+#[proc_macro_attribute]
+pub fn profile(args: TokenStream, item: TokenStream) -> TokenStream {
+    let mut byte_count: Option<Expr> = None;
+    let args_parser = syn::meta::parser(|meta| {
+        if meta.path.is_ident("bytes") {
+            byte_count = Some(meta.value()?.parse()?);
+            Ok(())
+        } else {
+            Err(meta.error("unsupported #[profile] argument, expected `bytes = <expr>`"))
+        }
+    });
+    parse_macro_input!(args with args_parser);
+    let function = parse_macro_input!(item as ItemFn);
+
+    if let Some(asyncness) = function.sig.asyncness {
+        return syn::Error::new_spanned(asyncness, "#[profile] does not support async functions").to_compile_error().into();
+    }
+
+    if cfg!(feature = "profile") { wrap_function(function, byte_count) } else { check_byte_count(function, byte_count) }
+}
+
+fn check_byte_count(function: ItemFn, byte_count: Option<Expr>) -> TokenStream {
+    let Some(byte_count) = byte_count else { return quote!(#function).into(); };
+    let ItemFn { attrs, vis, sig, block } = function;
+    let stmts = block.stmts;
+    quote!(
+        #(#attrs)* #vis #sig {
+            if false { let _ = &(#byte_count); }
+            #(#stmts)*
+        }
+    ).into()
+}
+
+fn wrap_function(function: ItemFn, byte_count: Option<Expr>) -> TokenStream {
+    let ItemFn { attrs, vis, sig, block } = function;
+    let tag = sig.ident.to_string();
+    let stmts = block.stmts;
+    let byte_count = byte_count.map_or_else(|| quote!(0), |byte_count| quote!(#byte_count));
+    // Mixed site hygiene keeps these bindings out of reach of the body, which shares their scope
+    let profile_block = quote_spanned!(Span::mixed_site()=>
+        let __profiler_index: usize = {
+            static PROFILE_INDEX: profiler::Lazy<usize> = profiler::Lazy::new(profiler::__GLOBAL_PROFILER__COUNTER__);
+            *PROFILE_INDEX
+        };
+        let __profiler_byte_count: u64 = #byte_count;
+        let __profile_block = profiler::ProfileBlock::new(#tag, __profiler_index, __profiler_byte_count);
+    );
+    quote!(
+        #(#attrs)* #vis #sig {
+            #profile_block
+            #(#stmts)*
+        }
+    ).into()
+}
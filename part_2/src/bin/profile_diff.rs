@@ -0,0 +1,144 @@
+use std::env;
+use std::fs;
+use std::io::{IsTerminal, Result, Error, ErrorKind::InvalidData};
+use std::process::ExitCode;
+
+use json_parser::{Json, JsonValue};
+use profiler::{ProfileReport, ProfileReportEntry};
+use profiler::report::json_unescape;
+use utils::printable_large_num;
+
+// Compares two reports saved through PROFILER_REPORT (json or csv) and flags tags whose
+// exclusive clocks grew by more than the threshold. Exits with 1 if any regression was found.
+// Changes are colored when stdout is a terminal, unless --no-color is given.
+fn main() -> ExitCode {
+    let no_color_arg = "--no-color";
+    let color = !env::args().any(|arg| arg == no_color_arg) && std::io::stdout().is_terminal();
+    let args: Vec<String> = env::args().filter(|arg| arg != no_color_arg).collect();
+
+    let usage = "\nUsage: \tprofile_diff [--no-color] [baseline_report] [new_report]\n\
+                          \tprofile_diff [--no-color] [baseline_report] [new_report] [regression threshold %]\n";
+
+    assert!(args.len() >= 3 && args.len() <= 4, "{}", usage);
+    let threshold_percent = if args.len() == 4 { args[3].parse::<f64>().expect(usage) } else { 5.0 };
+
+    let baseline = load_report(&args[1]).unwrap_or_else(|e| panic!("Failed to load baseline report {}: {}", args[1], e));
+    let new = load_report(&args[2]).unwrap_or_else(|e| panic!("Failed to load new report {}: {}", args[2], e));
+
+    let regression_count = print_diff(&baseline, &new, threshold_percent, color);
+    if regression_count > 0 {
+        println!("\n{} regression(s) above {:.1}%", regression_count, threshold_percent);
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
+
+const RED: &str = "\x1b[31m";
+const GREEN: &str = "\x1b[32m";
+const RESET: &str = "\x1b[0m";
+
+fn percent_change(baseline: u64, new: u64) -> f64 {
+    if baseline == 0 { if new == 0 { 0.0 } else { f64::INFINITY } } else { (new as f64 - baseline as f64) / baseline as f64 * 100.0 }
+}
+
+fn printable_bandwidth(entry: Option<&ProfileReportEntry>) -> String {
+    match entry {
+        Some(entry) if entry.processed_byte_count > 0 => format!("{:.2}gb/s", entry.bandwidth / (1024.0 * 1024.0 * 1024.0)),
+        _ => String::from("-")
+    }
+}
+
+// Returns the number of regressions
+fn print_diff(baseline: &ProfileReport, new: &ProfileReport, threshold_percent: f64, color: bool) -> usize {
+    let mut regression_count = 0;
+
+    // Baseline order first, then tags that only exist in the new report
+    let mut tags: Vec<&str> = baseline.entries.iter().map(|entry| entry.tag.as_str()).collect();
+    tags.extend(new.entries.iter().map(|entry| entry.tag.as_str()).filter(|tag| baseline.entry(tag).is_none()));
+
+    println!("\n{:<40}{:<20}{:<20}{:<12}{:<20}Bandwidth\n", "Tag", "Baseline clocks", "New clocks", "Delta", "Percent of total");
+    for tag in tags {
+        let baseline_entry = baseline.entry(tag);
+        let new_entry = new.entry(tag);
+        let baseline_clocks = baseline_entry.map_or(0, |entry| entry.elapsed_exclusive);
+        let new_clocks = new_entry.map_or(0, |entry| entry.elapsed_exclusive);
+
+        let delta = match (baseline_entry, new_entry) {
+            (Some(_), None) => String::from("removed"),
+            (None, Some(_)) => String::from("added"),
+            _ => format!("{:+.2}%", percent_change(baseline_clocks, new_clocks))
+        };
+        let percent_of_total = format!("{:.2} -> {:.2}",
+            baseline_clocks as f64 / baseline.total_clocks as f64 * 100.0,
+            new_clocks as f64 / new.total_clocks as f64 * 100.0);
+        let bandwidth = format!("{} -> {}", printable_bandwidth(baseline_entry), printable_bandwidth(new_entry));
+
+        let change = percent_change(baseline_clocks, new_clocks);
+        let change_color = if baseline_entry.is_some() && new_entry.is_some() && change > threshold_percent {
+            regression_count += 1;
+            RED
+        } else if baseline_entry.is_some() && new_entry.is_some() && change < -threshold_percent { GREEN } else { "" };
+        let change_color = if color { change_color } else { "" };
+        let reset = if change_color.is_empty() { "" } else { RESET };
+
+        println!("{}{:<40}{:<20}{:<20}{:<12}{:<20}{}{}", change_color, tag, printable_large_num(baseline_clocks), printable_large_num(new_clocks), delta, percent_of_total, bandwidth, reset);
+    }
+
+    let total_change = percent_change(baseline.total_clocks, new.total_clocks);
+    println!("\n{:<40}{:<20}{:<20}{:+.2}%", "Total runtime clocks:", printable_large_num(baseline.total_clocks), printable_large_num(new.total_clocks), total_change);
+    println!("{:<40}{:<20.3}{:<20.3}", "Total runtime ms:", baseline.total_millisecs(), new.total_millisecs());
+
+    regression_count
+}
+
+fn load_report(path: &str) -> Result<ProfileReport> {
+    if path.ends_with(".csv") {
+        ProfileReport::from_csv(&fs::read_to_string(path)?)
+    } else {
+        report_from_json(&fs::read(path)?)
+    }
+}
+
+fn report_from_json(json_bytes: &[u8]) -> Result<ProfileReport> {
+    let json = json_parser::parse_json_bytes(json_bytes)?;
+    let mut report = ProfileReport::default();
+
+    let mut root_context = json.get_root_context();
+    while let Some((key, value)) = json.get_next_element(&mut root_context) {
+        match (key, value) {
+            (b"cpu_freq", &JsonValue::Number(n)) => report.cpu_freq = n as u64,
+            (b"total_clocks", &JsonValue::Number(n)) => report.total_clocks = n as u64,
+            (b"clocks_profiled", &JsonValue::Number(n)) => report.clocks_profiled = n as u64,
+            (b"entries", entries_value) => {
+                let mut entries_context = json.open_collection(entries_value).ok_or_else(|| Error::new(InvalidData, "ERROR: Expected entries to be an array."))?;
+                while let Some((_, entry_value)) = json.get_next_element(&mut entries_context) {
+                    report.entries.push(entry_from_json(&json, entry_value)?);
+                }
+            },
+            _ => {}
+        }
+    }
+
+    if report.cpu_freq == 0 { return Err(Error::new(InvalidData, "ERROR: Profile report is missing cpu_freq.")); }
+    Ok(report)
+}
+
+fn entry_from_json(json: &Json, entry_value: &JsonValue) -> Result<ProfileReportEntry> {
+    let mut entry_context = json.open_collection(entry_value).ok_or_else(|| Error::new(InvalidData, "ERROR: Expected entries to contain objects."))?;
+    let mut entry = ProfileReportEntry::default();
+    while let Some((key, value)) = json.get_next_element(&mut entry_context) {
+        match (key, value) {
+            // Strings are returned with their escapes intact
+            (b"tag", JsonValue::String(s)) => entry.tag = json_unescape(&String::from_utf8_lossy(s)),
+            (b"invocations", &JsonValue::Number(n)) => entry.invocations = n as u64,
+            (b"elapsed_exclusive", &JsonValue::Number(n)) => entry.elapsed_exclusive = n as u64,
+            (b"elapsed_inclusive", &JsonValue::Number(n)) => entry.elapsed_inclusive = n as u64,
+            (b"processed_byte_count", &JsonValue::Number(n)) => entry.processed_byte_count = n as u64,
+            (b"bandwidth", &JsonValue::Number(n)) => entry.bandwidth = n,
+            (b"sample_period", &JsonValue::Number(n)) => entry.sample_period = n as u64,
+            _ => {}
+        }
+    }
+    Ok(entry)
+}
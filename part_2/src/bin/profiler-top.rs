@@ -0,0 +1,80 @@
+use std::collections::HashMap;
+use std::env;
+use std::io::BufReader;
+use std::process::ExitCode;
+
+use profiler::{live, ProfileReport};
+use utils::printable_large_num;
+
+// Connects to a process publishing live profiler results (PROFILER_LIVE or profiler_publish_live!) and
+// redraws the anchor table for every snapshot, sorted by the clocks spent since the previous one.
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().collect();
+
+    let usage = "\nUsage: \tprofiler-top [address]\n\
+                          \tprofiler-top [address] [max rows]\n\
+                 \taddress is unix:<socket path> or <host>:<port> on a loopback address\n";
+
+    assert!(args.len() >= 2 && args.len() <= 3, "{}", usage);
+    let address = &args[1];
+    let max_rows = if args.len() == 3 { args[2].parse::<usize>().expect(usage) } else { 30 };
+
+    let stream = live::connect(address).unwrap_or_else(|e| panic!("Failed to connect to {}: {}", address, e));
+    let mut reader = BufReader::new(stream);
+    let mut previous: Option<ProfileReport> = None;
+    loop {
+        match live::read_frame(&mut reader) {
+            Ok(Some(report)) => {
+                print_frame(address, &report, previous.as_ref(), max_rows);
+                previous = Some(report);
+            },
+            Ok(None) => {
+                println!("\nConnection to {} closed.", address);
+                return ExitCode::SUCCESS;
+            },
+            Err(e) => {
+                eprintln!("\nFailed to read from {}: {}", address, e);
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+}
+
+const CLEAR_SCREEN: &str = "\x1b[2J\x1b[H";
+
+fn print_frame(address: &str, report: &ProfileReport, previous: Option<&ProfileReport>, max_rows: usize) {
+    print!("{}", CLEAR_SCREEN);
+    let total_percent = report.clocks_profiled as f64 / report.total_clocks.max(1) as f64 * 100.0;
+    println!("profiler-top: {}    Session: {:.3}ms    Profiled: {:.2}%", address, report.total_millisecs(), total_percent);
+    if report.entries.is_empty() {
+        println!("\nWaiting for profile blocks to close...");
+        return;
+    }
+
+    // Clocks since the previous snapshot, everything counts as recent for the first one
+    let previous_clocks: HashMap<&str, u64> = previous.map(|previous| previous.entries.iter().map(|entry| (entry.tag.as_str(), entry.elapsed_exclusive)).collect()).unwrap_or_default();
+    let previous_total = previous.map_or(0, |previous| previous.total_clocks);
+    let recent_total = report.total_clocks.saturating_sub(previous_total).max(1);
+
+    let mut rows: Vec<(u64, &profiler::ProfileReportEntry)> = report.entries.iter()
+        .map(|entry| (entry.elapsed_exclusive.saturating_sub(*previous_clocks.get(entry.tag.as_str()).unwrap_or(&0)), entry))
+        .collect();
+    rows.sort_by(|(a_recent, a), (b_recent, b)| b_recent.cmp(a_recent).then(b.elapsed_exclusive.cmp(&a.elapsed_exclusive)));
+
+    println!("\n{:<40}{:<10}{:<25}{:<10}{:<15}Bandwidth\n", "Tag (Invocations)", "Recent %", "Clocks", "Percent", "w/ Children");
+    for (recent_clocks, entry) in rows.iter().take(max_rows) {
+        let title_str = if entry.sample_period > 1 {
+            format!("{} ({}, sampled 1/{}):", entry.tag, printable_large_num(entry.invocations), entry.sample_period)
+        } else {
+            format!("{} ({}):", entry.tag, printable_large_num(entry.invocations))
+        };
+        let recent_percent = *recent_clocks as f64 / recent_total as f64 * 100.0;
+        let percent_exclusive = entry.elapsed_exclusive as f64 / report.total_clocks as f64 * 100.0;
+        let percent_inclusive = entry.elapsed_inclusive as f64 / report.total_clocks as f64 * 100.0;
+        let bandwidth = if entry.processed_byte_count == 0 { String::new() } else { format!("{:.2}gb/s", entry.bandwidth / (1024.0 * 1024.0 * 1024.0)) };
+        println!("{:<40}{:<10.2}{:<25}{:<10.2}{:<15.2}{}", title_str, recent_percent, printable_large_num(entry.elapsed_exclusive), percent_exclusive, percent_inclusive, bandwidth);
+    }
+    if rows.len() > max_rows {
+        println!("... {} more", rows.len() - max_rows);
+    }
+}
@@ -8,6 +8,10 @@ use haversine_gen::parse_haversine_binary_file;
 
 use profiler::*;
 
+#[cfg(feature = "alloc-counts")]
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
 struct PointPair{ x0: f64, y0: f64, x1: f64, y1: f64 }
 
 fn main() {
@@ -56,9 +60,8 @@ fn main() {
     time_teardown!();
 }
 
+#[profile]
 fn pairs_from_root_json(json: &Json) -> Vec<PointPair> {
-    time_function!();
-    
     let mut point_pairs = Vec::<PointPair>::new();
         
     let mut root_object_context = json.get_root_context();
@@ -79,11 +82,11 @@ fn pairs_from_root_json(json: &Json) -> Vec<PointPair> {
         }
     } else { panic!("Expected object to have pairs array."); }
 
-    return point_pairs;
+    point_pairs
 }
 
 #[allow(dead_code)]
-fn print_debug(point_pairs: &Vec<PointPair>, haversine_vals: &Vec<f64>, haversine_mean: f64) {
+fn print_debug(point_pairs: &[PointPair], haversine_vals: &[f64], haversine_mean: f64) {
     
     println!("Point pairs:");
     for (i,pp) in point_pairs.iter().enumerate() {
@@ -0,0 +1,100 @@
+// Parses the Chrome trace written at teardown:
+//      cargo test --test chrome_trace --features profile
+#![cfg(feature = "profile")]
+
+use std::collections::HashMap;
+
+use json_parser::{Json, JsonValue};
+use profiler::*;
+
+struct TraceEvent {
+    name: String,
+    phase: String,
+    tid: u64,
+    ts: f64,
+    dur: f64,
+    thread_name: Option<String> // args.name of "thread_name" metadata events
+}
+
+fn parse_event(json: &Json, value: &JsonValue) -> TraceEvent {
+    let mut event = TraceEvent { name: String::new(), phase: String::new(), tid: 0, ts: 0.0, dur: 0.0, thread_name: None };
+    let mut context = json.open_collection(value).expect("Expected trace events to be objects.");
+    while let Some((key, value)) = json.get_next_element(&mut context) {
+        match (key, value) {
+            (b"name", JsonValue::String(s)) => event.name = String::from_utf8_lossy(s).into_owned(),
+            (b"ph", JsonValue::String(s)) => event.phase = String::from_utf8_lossy(s).into_owned(),
+            (b"tid", &JsonValue::Number(n)) => event.tid = n as u64,
+            (b"ts", &JsonValue::Number(n)) => event.ts = n,
+            (b"dur", &JsonValue::Number(n)) => event.dur = n,
+            (b"args", args) => {
+                let mut args_context = json.open_collection(args).expect("Expected args to be an object.");
+                while let Some((key, value)) = json.get_next_element(&mut args_context) {
+                    if let (b"name", JsonValue::String(s)) = (key, value) { event.thread_name = Some(String::from_utf8_lossy(s).into_owned()); }
+                }
+            },
+            _ => {}
+        }
+    }
+    event
+}
+
+fn parse_trace(path: &str) -> Vec<TraceEvent> {
+    let bytes = std::fs::read(path).unwrap();
+    let json = json_parser::parse_json_bytes(&bytes).expect("Chrome trace is not valid json.");
+    let mut root_context = json.get_root_context();
+    let mut events = Vec::new();
+    while let Some((key, value)) = json.get_next_element(&mut root_context) {
+        if key != b"traceEvents" { continue; }
+        let mut events_context = json.open_collection(value).expect("Expected traceEvents to be an array.");
+        while let Some((_, event_value)) = json.get_next_element(&mut events_context) {
+            events.push(parse_event(&json, event_value));
+        }
+    }
+    events
+}
+
+// Timestamps and durations are rounded to the nanosecond separately
+fn contains(outer: &TraceEvent, inner: &TraceEvent) -> bool {
+    outer.ts <= inner.ts + 0.002 && inner.ts + inner.dur <= outer.ts + outer.dur + 0.002
+}
+
+#[test]
+fn blocks_nest_per_thread() {
+    GLOBAL_PROFILER.init();
+    let path = std::env::temp_dir().join(format!("chrome_trace_{}.json", std::process::id()));
+    let path = path.to_str().unwrap();
+    GLOBAL_PROFILER.enable_trace(path, 1000);
+
+    {
+        time_block!("outer");
+        for _ in 0..2 {
+            time_block!("inner");
+            std::hint::black_box(0);
+        }
+    }
+    std::thread::Builder::new().name(String::from("trace worker")).spawn(|| {
+        time_block!("worker");
+    }).unwrap().join().unwrap();
+    GLOBAL_PROFILER.print_and_deinit();
+
+    let events = parse_trace(path);
+    std::fs::remove_file(path).unwrap();
+    let thread_ids: HashMap<String, u64> = events.iter()
+        .filter(|event| event.phase == "M" && event.name == "thread_name")
+        .map(|event| (event.thread_name.clone().unwrap(), event.tid))
+        .collect();
+    let main_tid = thread_ids[std::thread::current().name().unwrap()];
+    let worker_tid = thread_ids["trace worker"];
+    assert_ne!(main_tid, worker_tid);
+
+    let blocks = |name: &str| events.iter().filter(|event| event.phase == "X" && event.name == name).collect::<Vec<_>>();
+    let (outer, inner, worker) = (blocks("outer"), blocks("inner"), blocks("worker"));
+    assert_eq!((outer.len(), inner.len(), worker.len()), (1, 2, 1));
+    assert!(outer.iter().chain(inner.iter()).all(|event| event.tid == main_tid));
+    assert_eq!(worker[0].tid, worker_tid);
+    assert!(inner.iter().all(|event| contains(outer[0], event)));
+    assert!(inner[0].ts + inner[0].dur <= inner[1].ts + 0.002, "Sibling blocks overlap");
+    // Every block nests in the application span
+    let application = blocks("Application");
+    assert!(events.iter().filter(|event| event.phase == "X").all(|event| contains(application[0], event)));
+}
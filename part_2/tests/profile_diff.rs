@@ -0,0 +1,52 @@
+// Runs the profile_diff binary on json reports written by ProfileReport::to_json
+
+use std::process::Command;
+
+use profiler::{ProfileReport, ProfileReportEntry};
+
+const ESCAPED_TAG: &str = "parse \"quoted\" C:\\path\\u0041\tend";
+
+fn report(parse_clocks: u64) -> ProfileReport {
+    let cpu_freq = 1_000_000_000;
+    ProfileReport {
+        cpu_freq, total_clocks: 2_000_000, clocks_profiled: 1_500_000,
+        entries: vec![
+            ProfileReportEntry::new("fs::read", 1, 500_000, 500_000, 4096, cpu_freq),
+            ProfileReportEntry::new(ESCAPED_TAG, 3, parse_clocks, parse_clocks, 0, cpu_freq),
+        ]
+    }
+}
+
+// Exit code and stdout of profile_diff for the two reports, stdout is piped rather than a terminal
+fn diff(baseline: &ProfileReport, new: &ProfileReport, name: &str, args: &[&str]) -> (Option<i32>, String) {
+    let dir = std::env::temp_dir();
+    let baseline_path = dir.join(format!("profile_diff_{}_{}_baseline.json", name, std::process::id()));
+    let new_path = dir.join(format!("profile_diff_{}_{}_new.json", name, std::process::id()));
+    std::fs::write(&baseline_path, baseline.to_json()).unwrap();
+    std::fs::write(&new_path, new.to_json()).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_profile_diff")).arg(&baseline_path).arg(&new_path).args(args).output().unwrap();
+    std::fs::remove_file(baseline_path).unwrap();
+    std::fs::remove_file(new_path).unwrap();
+    (output.status.code(), String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[test]
+fn unchanged_reports_pass() {
+    let (code, stdout) = diff(&report(1_000_000), &report(1_000_000), "unchanged", &[]);
+    assert_eq!(code, Some(0), "{}", stdout);
+    // Matched across both reports after unescaping, so neither added nor removed
+    assert!(stdout.lines().any(|line| line.starts_with(ESCAPED_TAG) && line.contains("+0.00%")), "{}", stdout);
+}
+
+#[test]
+fn regressions_fail() {
+    let (code, stdout) = diff(&report(1_000_000), &report(1_200_000), "regression", &[]);
+    assert_eq!(code, Some(1), "{}", stdout);
+    assert!(stdout.contains("1 regression(s) above 5.0%"), "{}", stdout);
+    assert!(stdout.lines().any(|line| line.starts_with(ESCAPED_TAG) && line.contains("+20.00%")), "{}", stdout);
+    assert!(!stdout.contains('\x1b'), "Colored output to a pipe: {}", stdout);
+
+    let (code, stdout) = diff(&report(1_000_000), &report(1_200_000), "regression_no_color", &["--no-color"]);
+    assert_eq!(code, Some(1), "{}", stdout);
+    assert!(!stdout.contains('\x1b'), "{}", stdout);
+}
@@ -0,0 +1,83 @@
+// Expands every profiler macro, run under both configurations:
+//      cargo test --test profiler_macros
+//      cargo test --test profiler_macros --features profile
+
+use std::cell::Cell;
+
+use profiler::*;
+
+fn counted_bytes(evaluations: &Cell<u32>, byte_count: u64) -> u64 {
+    evaluations.set(evaluations.get() + 1);
+    byte_count
+}
+
+fn bandwidth_function(evaluations: &Cell<u32>, bytes: &[u8]) -> usize {
+    time_bandwidth_function!(counted_bytes(evaluations, bytes.len() as u64));
+    bytes.len()
+}
+
+fn plain_function() -> u32 {
+    time_function!();
+    1
+}
+
+fn sampled_function() -> u32 {
+    time_sampled_function!(4);
+    1
+}
+
+#[profile]
+fn attribute_function() -> u32 { 2 }
+
+// Without profiling, `evaluations` is only used by the type checked byte count
+#[profile(bytes = counted_bytes(evaluations, 16))]
+fn attribute_bandwidth_function(evaluations: &Cell<u32>) -> u32 { 3 }
+
+// Only compiled, the macro must not rely on names imported by the caller
+#[allow(dead_code)]
+mod without_imports {
+    fn setup() { profiler::profiler_setup_defer_teardown!(); }
+}
+
+#[test]
+fn every_macro_expands() {
+    profiler_setup_defer_teardown!();
+    profiler_set_timer_read!(CpuTimerRead::Unserialized);
+    profiler_enable_perf_counters!(&[PerfCounter::Cycles, PerfCounter::Instructions]);
+    profiler_enable_call_tree!();
+    profiler_enable_folded_stacks!(&std::env::temp_dir().join("profiler_macros.folded").to_string_lossy());
+    profiler_enable_trace!(&std::env::temp_dir().join("profiler_macros.trace.json").to_string_lossy(), 1024);
+    profiler_enable_block_stats!();
+    profiler_enable_page_faults!();
+    profiler_enable_overhead_subtraction!();
+    profiler_enable_tracing_spans!();
+    profiler_publish_live!("127.0.0.1:0");
+    profiler_stop!();
+    profiler_start!();
+    profiler_reset!();
+
+    let evaluations = Cell::new(0);
+    {
+        time_block!("block");
+        time_bandwidth_block!("bandwidth block", counted_bytes(&evaluations, 8));
+        time_named_block!(String::from("named block"));
+        time_named_block!("named block {}", 2);
+        time_named_bandwidth_block!(format!("named bandwidth block {}", 3), counted_bytes(&evaluations, 8));
+        time_sampled_block!("sampled block", 16);
+    }
+    assert_eq!(bandwidth_function(&evaluations, &[0; 4]), 4);
+    assert_eq!(plain_function() + sampled_function() + attribute_function() + attribute_bandwidth_function(&evaluations), 7);
+
+    // Statements are run in both configurations
+    time_section!("section", let a = 1; let b = a + 1);
+    time_assignment_rhs!(let c = b + 1);
+    time_assignment!(let d = c + 1);
+    time_assignments!(let e = d + 1; let f = e + 1;);
+    assert_eq!(f, 6);
+
+    // Byte counts are only evaluated when profiling
+    let expected_evaluations = if cfg!(feature = "profile") { 4 } else { 0 };
+    assert_eq!(evaluations.get(), expected_evaluations);
+
+    time_teardown!();
+}
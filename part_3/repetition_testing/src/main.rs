@@ -177,7 +177,22 @@ fn get_page_faults(process_handle: HANDLE) -> u32 {
 }
 
 fn main() {
-    let cpu_freq = clocks::measure_cpu_freq(1000);
+    let args: Vec<String> = std::env::args().collect();
+    let usage = "Usage: repetition_testing [rdtsc/rdtscp/lfence/cpuid]";
+    assert!(args.len() <= 2, "{}", usage);
+    let timer_read = match args.get(1) {
+        Some(name) => clocks::CpuTimerRead::from_name(name).unwrap_or_else(|| panic!("{}\ntimer read value was {}", usage, name)),
+        None => clocks::CpuTimerRead::default(),
+    };
+
+    let cpu_freq = clocks::cached_cpu_freq().median;
+
+    println!("--- timer read overhead ---");
+    for read in clocks::CpuTimerRead::ALL {
+        let overhead = clocks::measure_timer_read_overhead(read, 1_000_000);
+        let selected = if read == timer_read { " (selected)" } else { "" };
+        println!("{:<8} {} clocks{}", read.name(), overhead, selected);
+    }
     let process_id : u32;
     let process_handle : HANDLE;
     unsafe {
@@ -219,9 +234,9 @@ fn main() {
 
             loop {
                 let starting_page_faults = get_page_faults(process_handle);
-                let starting_stamp = clocks::read_cpu_timer();
+                let starting_stamp = timer_read.read();
                 let results = (*action).action();
-                let ending_stamp = clocks::read_cpu_timer();
+                let ending_stamp = timer_read.read();
                 let ending_page_faults = get_page_faults(process_handle);
                 let diff_stamp = ending_stamp - starting_stamp;
                 let diff_page_faults = ending_page_faults - starting_page_faults;